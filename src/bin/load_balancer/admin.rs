@@ -0,0 +1,78 @@
+use std::io::{Error as IoError, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::lifecycle::Lifecycle;
+use crate::listener::AcceptLoop;
+use crate::metrics::Metrics;
+
+/// Serves `GET /metrics`, `GET /status` and, if `allow_shutdown` is set,
+/// `POST /shutdown` on a separate listener so scrapes never compete with
+/// proxied traffic.
+pub fn spawn_admin(
+    addr: &str,
+    metrics: Arc<Metrics>,
+    lifecycle: Arc<Lifecycle>,
+    allow_shutdown: bool
+) -> Result<AcceptLoop, IoError> {
+    let admin = AcceptLoop::spawn(addr, move |stream| {
+        if let Err(e) = handle_admin(stream, &metrics, &lifecycle, allow_shutdown) {
+            eprintln!("Error handling admin connection: {:?}", e);
+        }
+    })?;
+    println!("Admin listening on {}", admin.addr());
+
+    Ok(admin)
+}
+
+fn handle_admin(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    lifecycle: &Lifecycle,
+    allow_shutdown: bool
+) -> Result<(), IoError> {
+    let mut buffer = [0; 1024];
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let bytes_read = stream.read(&mut buffer)?;
+    let request = String::from_utf8_lossy(&buffer[..bytes_read]);
+
+    let response = if request.starts_with("GET /metrics ") {
+        // Exemplars only exist in OpenMetrics, so it is served to scrapers
+        // that ask for it when they are enabled; everyone else gets the
+        // Prometheus text format.
+        let openmetrics = metrics.exemplars_enabled()
+            && request.to_ascii_lowercase().contains("application/openmetrics-text");
+        let content_type = if openmetrics {
+            "application/openmetrics-text; version=1.0.0; charset=utf-8"
+        } else {
+            "text/plain; version=0.0.4"
+        };
+        let body = metrics.render(openmetrics);
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        )
+    } else if request.starts_with("GET /status ") {
+        let body = format!(
+            "status: {}\nactive_connections: {}\n",
+            if lifecycle.is_draining() { "draining" } else { "serving" },
+            lifecycle.active_connections()
+        );
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else if allow_shutdown && request.starts_with("POST /shutdown ") {
+        lifecycle.request_shutdown();
+        "HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
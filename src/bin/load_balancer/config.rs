@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::lifecycle::ShutdownStep;
+
+/// How background health checks are spread out over time.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HealthScheduler {
+    /// Every backend gets its own timer and is probed once per interval.
+    Independent,
+    /// A single scheduler paces probes across the whole fleet so no more than
+    /// `checks_per_second` go out, speeding up only when needed to keep every
+    /// backend within its interval.
+    RatePaced { checks_per_second: f64 },
+}
+
+/// The request sent to check a backend and the status that counts as healthy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheck {
+    pub method: String,
+    pub path: String,
+    pub expect_status: u16,
+}
+
+/// Health check settings given on a `backend` line. Anything left unset
+/// falls back to the global `health_*` keys.
+#[derive(Clone, Debug, Default)]
+struct HealthCheckOverride {
+    method: Option<String>,
+    path: Option<String>,
+    expect_status: Option<u16>,
+}
+
+/// What happens to the rest of a request body once the backend has answered
+/// without reading all of it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EarlyResponse {
+    /// Close the client connection after sending the response.
+    Close,
+    /// Read and discard the rest of the body so the client connection can be
+    /// kept alive.
+    Drain,
+}
+
+/// Which headers describe the original client to backends.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ForwardedHeaders {
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`.
+    XForwarded,
+    /// A single RFC 7239 `Forwarded` header.
+    Forwarded,
+    Both,
+    None,
+}
+
+/// How many connections the pool keeps per backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PoolSizing {
+    /// Fixed limits for every backend.
+    Static { max_idle: usize, max_connections: usize },
+    /// A connection cap that follows each backend's rolling peak in-use count
+    /// plus `headroom` (a fraction of the peak), and a floor of idle
+    /// connections kept open that follows the peak itself. Both are
+    /// re-evaluated every `adjust_interval` and kept within the min/max
+    /// bounds, and demand above the cap raises it right away; `max_idle` also
+    /// caps idle connections.
+    Adaptive {
+        headroom: f64,
+        min_idle: usize,
+        max_idle: usize,
+        min_connections: usize,
+        max_connections: usize,
+        adjust_interval: Duration,
+    },
+}
+
+/// How requests are pinned to a backend, if at all.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StickySessions {
+    Off,
+    /// Requests from one client IP go to the same backend while it is healthy.
+    ClientIp,
+}
+
+/// Which connection to close when the accept queue is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueuePolicy {
+    /// Turn away the connection that was just accepted.
+    DropNewest,
+    /// Close the connection that has been waiting longest and queue the new one.
+    DropOldest,
+}
+
+/// Body format for errors the balancer answers with itself.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorFormat {
+    Plain,
+    /// `{"error":"<code>","message":"<text>"}` for API clients.
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Data-plane addresses; `listen` may be repeated.
+    pub listen: Vec<String>,
+    /// Where `/metrics` is served.
+    pub admin_listen: String,
+    pub backends: Vec<String>,
+    /// Default health check; see `health_check_for` for per-backend ones.
+    pub health_check: HealthCheck,
+    backend_health: HashMap<String, HealthCheckOverride>,
+    pub health_interval: Duration,
+    pub health_scheduler: HealthScheduler,
+    /// TRACE echoes the request back, which lets scripts read headers such as
+    /// cookies they otherwise couldn't (cross-site tracing), so it is answered
+    /// with 405 at the balancer unless explicitly allowed.
+    pub allow_trace: bool,
+    /// Debug mode for suspected response crosstalk: tag every forwarded
+    /// request with a unique probe header and eject pooled connections whose
+    /// responses don't line up with the request that was sent.
+    pub probe_connections: bool,
+    /// Requests a client may have queued on one connection, counting the one
+    /// being served. Going over gets a 429 and the connection is closed.
+    pub max_pipelined_requests: usize,
+    /// Requests served on one keep-alive connection before it is closed.
+    pub max_requests_per_connection: usize,
+    pub early_response: EarlyResponse,
+    /// How far back the per-backend selection share looks.
+    pub selection_window: Duration,
+    /// Print the selection share once per window.
+    pub log_selection_share: bool,
+    /// Whether `POST /shutdown` on the admin listener is served. The admin
+    /// listener has no authentication, so anyone who can reach it could stop
+    /// the balancer.
+    pub admin_shutdown: bool,
+    /// The steps run once shutdown is requested, each exactly once.
+    pub shutdown_order: Vec<ShutdownStep>,
+    /// Longest the `drain` step waits for open client connections.
+    pub drain_timeout: Duration,
+    pub forwarded_headers: ForwardedHeaders,
+    pub error_format: ErrorFormat,
+    /// Serve client connections on this many worker threads instead of a
+    /// thread per connection.
+    pub workers: Option<usize>,
+    /// Accepted connections waiting for a free worker, at most.
+    pub accept_queue_size: usize,
+    pub accept_queue_policy: QueuePolicy,
+    pub sticky_sessions: StickySessions,
+    /// With sticky sessions on, a request whose sticky backend already has
+    /// more than this many connections checked out goes through round-robin
+    /// instead. `None` never breaks stickiness.
+    pub sticky_max_in_use: Option<usize>,
+    pub pool_sizing: PoolSizing,
+    /// Forward the client's W3C `traceparent`, or start a trace for requests
+    /// that don't have one.
+    pub trace_propagation: bool,
+    /// Attach trace IDs to the request duration histogram as OpenMetrics
+    /// exemplars. Needs `trace_propagation`.
+    pub metrics_exemplars: bool,
+    /// Internal hosts whose absolute links in HTML responses are rewritten to
+    /// the host the client asked for; `html_rewrite_host` may be repeated.
+    /// Empty turns rewriting off.
+    pub html_rewrite_hosts: Vec<String>,
+    /// HTML bodies larger than this are passed through unchanged.
+    pub html_rewrite_max_bytes: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: vec!["127.0.0.1:8080".to_string()],
+            admin_listen: "127.0.0.1:8090".to_string(),
+            backends: vec![
+                "127.0.0.1:8081".to_string(),
+                "127.0.0.1:8082".to_string(),
+                "127.0.0.1:8083".to_string(),
+            ],
+            health_check: HealthCheck {
+                method: "GET".to_string(),
+                path: "/health".to_string(),
+                expect_status: 200,
+            },
+            backend_health: HashMap::new(),
+            health_interval: Duration::from_secs(5),
+            health_scheduler: HealthScheduler::Independent,
+            allow_trace: false,
+            probe_connections: false,
+            max_pipelined_requests: 16,
+            max_requests_per_connection: 1000,
+            early_response: EarlyResponse::Close,
+            selection_window: Duration::from_secs(60),
+            log_selection_share: false,
+            admin_shutdown: false,
+            shutdown_order: vec![ShutdownStep::StopData, ShutdownStep::Drain, ShutdownStep::StopAdmin],
+            drain_timeout: Duration::from_secs(30),
+            forwarded_headers: ForwardedHeaders::XForwarded,
+            error_format: ErrorFormat::Plain,
+            workers: None,
+            accept_queue_size: 128,
+            accept_queue_policy: QueuePolicy::DropNewest,
+            sticky_sessions: StickySessions::Off,
+            sticky_max_in_use: None,
+            pool_sizing: PoolSizing::Static { max_idle: 8, max_connections: 64 },
+            trace_propagation: false,
+            metrics_exemplars: false,
+            html_rewrite_hosts: Vec::new(),
+            html_rewrite_max_bytes: 256 * 1024,
+        }
+    }
+}
+
+impl Config {
+    /// Reads a config file made of `key = value` lines. Blank lines and lines
+    /// starting with `#` are ignored, and `backend` may be repeated once per
+    /// server. Anything not set keeps its default.
+    ///
+    /// A `backend` line may override the health check for that server by
+    /// following the address with `health_path=`, `health_method=` and
+    /// `health_expect_status=` settings.
+    pub fn load(path: &str) -> Result<Self, IoError> {
+        let contents = fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    fn parse(contents: &str) -> Result<Self, IoError> {
+        let mut config = Config::default();
+        let mut listen = Vec::new();
+        let mut backends = Vec::new();
+        let mut scheduler = "independent".to_string();
+        let mut checks_per_second = 10.0;
+        let mut pool_sizing = "static".to_string();
+        let mut pool_headroom = 0.25;
+        let mut pool_min_idle = 1;
+        let mut pool_max_idle = 8;
+        let mut pool_min_connections = 4;
+        let mut pool_max_connections = 64;
+        let mut pool_adjust_interval = Duration::from_secs(10);
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| invalid(format!("line {}: expected `key = value`", number + 1)))?;
+
+            match key {
+                "listen" => listen.push(value.to_string()),
+                "admin_listen" => config.admin_listen = value.to_string(),
+                "backend" => {
+                    let (addr, health) = parse_backend(value)?;
+                    if let Some(health) = health {
+                        config.backend_health.insert(addr.clone(), health);
+                    }
+                    backends.push(addr);
+                }
+                "health_path" => config.health_check.path = value.to_string(),
+                "health_method" => config.health_check.method = value.to_string(),
+                "health_expect_status" => config.health_check.expect_status = parse_number(key, value)?,
+                "health_interval_ms" => config.health_interval = Duration::from_millis(parse_number(key, value)?),
+                "health_scheduler" => scheduler = value.to_string(),
+                "health_checks_per_second" => checks_per_second = parse_number(key, value)?,
+                "allow_trace" => config.allow_trace = parse_bool(key, value)?,
+                "probe_connections" => config.probe_connections = parse_bool(key, value)?,
+                "max_pipelined_requests" => config.max_pipelined_requests = parse_number(key, value)?,
+                "max_requests_per_connection" => config.max_requests_per_connection = parse_number(key, value)?,
+                "selection_window_secs" => config.selection_window = Duration::from_secs(parse_number(key, value)?),
+                "log_selection_share" => config.log_selection_share = parse_bool(key, value)?,
+                "admin_shutdown" => config.admin_shutdown = parse_bool(key, value)?,
+                "shutdown_order" => config.shutdown_order = parse_shutdown_order(value)?,
+                "drain_timeout_secs" => config.drain_timeout = Duration::from_secs(parse_number(key, value)?),
+                "forwarded_headers" => {
+                    config.forwarded_headers = match value {
+                        "x-forwarded" => ForwardedHeaders::XForwarded,
+                        "forwarded" => ForwardedHeaders::Forwarded,
+                        "both" => ForwardedHeaders::Both,
+                        "none" => ForwardedHeaders::None,
+                        _ => return Err(invalid(format!("unknown forwarded_headers `{}`", value))),
+                    }
+                }
+                "pool_sizing" => pool_sizing = value.to_string(),
+                "pool_headroom" => pool_headroom = parse_number(key, value)?,
+                "pool_min_idle" => pool_min_idle = parse_number(key, value)?,
+                "pool_max_idle" => pool_max_idle = parse_number(key, value)?,
+                "pool_min_connections" => pool_min_connections = parse_number(key, value)?,
+                "pool_max_connections" => pool_max_connections = parse_number(key, value)?,
+                "pool_adjust_interval_secs" => pool_adjust_interval = Duration::from_secs(parse_number(key, value)?),
+                "trace_propagation" => config.trace_propagation = parse_bool(key, value)?,
+                "metrics_exemplars" => config.metrics_exemplars = parse_bool(key, value)?,
+                "html_rewrite_host" => config.html_rewrite_hosts.push(value.to_string()),
+                "html_rewrite_max_bytes" => config.html_rewrite_max_bytes = parse_number(key, value)?,
+                "error_format" => {
+                    config.error_format = match value {
+                        "plain" => ErrorFormat::Plain,
+                        "json" => ErrorFormat::Json,
+                        _ => return Err(invalid(format!("unknown error_format `{}`", value))),
+                    }
+                }
+                "workers" => config.workers = Some(parse_number(key, value)?),
+                "accept_queue_size" => config.accept_queue_size = parse_number(key, value)?,
+                "accept_queue_policy" => {
+                    config.accept_queue_policy = match value {
+                        "drop_newest" => QueuePolicy::DropNewest,
+                        "drop_oldest" => QueuePolicy::DropOldest,
+                        _ => return Err(invalid(format!("unknown accept_queue_policy `{}`", value))),
+                    }
+                }
+                "sticky_sessions" => {
+                    config.sticky_sessions = match value {
+                        "off" => StickySessions::Off,
+                        "client_ip" => StickySessions::ClientIp,
+                        _ => return Err(invalid(format!("unknown sticky_sessions `{}`", value))),
+                    }
+                }
+                "sticky_max_in_use" => config.sticky_max_in_use = Some(parse_number(key, value)?),
+                "early_response" => {
+                    config.early_response = match value {
+                        "close" => EarlyResponse::Close,
+                        "drain" => EarlyResponse::Drain,
+                        _ => return Err(invalid(format!("unknown early_response `{}`", value))),
+                    }
+                }
+                _ => return Err(invalid(format!("line {}: unknown key `{}`", number + 1, key))),
+            }
+        }
+
+        if config.max_pipelined_requests == 0 || config.max_requests_per_connection == 0 {
+            return Err(invalid("per-connection request limits must be at least 1".to_string()));
+        }
+
+        if config.workers == Some(0) || config.accept_queue_size == 0 {
+            return Err(invalid("workers and accept_queue_size must be at least 1".to_string()));
+        }
+
+        if config.selection_window.is_zero() {
+            return Err(invalid("selection_window_secs must be at least 1".to_string()));
+        }
+
+        if config.health_interval.is_zero() {
+            return Err(invalid("health_interval_ms must be at least 1".to_string()));
+        }
+
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+        if !backends.is_empty() {
+            config.backends = backends;
+        }
+
+        config.health_scheduler = match scheduler.as_str() {
+            "independent" => HealthScheduler::Independent,
+            "rate_paced" if checks_per_second > 0.0 => HealthScheduler::RatePaced { checks_per_second },
+            "rate_paced" => return Err(invalid("health_checks_per_second must be positive".to_string())),
+            other => return Err(invalid(format!("unknown health_scheduler `{}`", other))),
+        };
+
+        if config.metrics_exemplars && !config.trace_propagation {
+            return Err(invalid("metrics_exemplars needs trace_propagation = true".to_string()));
+        }
+
+        if pool_max_connections == 0 || pool_min_idle > pool_max_idle || pool_min_connections > pool_max_connections {
+            return Err(invalid("pool bounds must satisfy min <= max, with max connections at least 1".to_string()));
+        }
+
+        config.pool_sizing = match pool_sizing.as_str() {
+            "static" => PoolSizing::Static {
+                max_idle: pool_max_idle,
+                max_connections: pool_max_connections,
+            },
+            "adaptive" if pool_headroom < 0.0 => return Err(invalid("pool_headroom must not be negative".to_string())),
+            "adaptive" if pool_adjust_interval.is_zero() => {
+                return Err(invalid("pool_adjust_interval_secs must be at least 1".to_string()));
+            }
+            "adaptive" => PoolSizing::Adaptive {
+                headroom: pool_headroom,
+                min_idle: pool_min_idle,
+                max_idle: pool_max_idle,
+                // A cap of zero would refuse every request.
+                min_connections: pool_min_connections.max(1),
+                max_connections: pool_max_connections,
+                adjust_interval: pool_adjust_interval,
+            },
+            other => return Err(invalid(format!("unknown pool_sizing `{}`", other))),
+        };
+
+        Ok(config)
+    }
+
+    /// The health check for `backend`: its own settings where it has them,
+    /// the global ones otherwise.
+    pub fn health_check_for(&self, backend: &str) -> HealthCheck {
+        let mut check = self.health_check.clone();
+
+        if let Some(health) = self.backend_health.get(backend) {
+            if let Some(method) = &health.method {
+                check.method = method.clone();
+            }
+            if let Some(path) = &health.path {
+                check.path = path.clone();
+            }
+            if let Some(expect_status) = health.expect_status {
+                check.expect_status = expect_status;
+            }
+        }
+
+        check
+    }
+}
+
+/// Splits a `backend` value into the address and any health check settings
+/// that follow it.
+fn parse_backend(value: &str) -> Result<(String, Option<HealthCheckOverride>), IoError> {
+    let mut parts = value.split_whitespace();
+    let addr = parts
+        .next()
+        .ok_or_else(|| invalid("backend needs an address".to_string()))?
+        .to_string();
+    // Connections are made straight to this address, without a DNS lookup.
+    if addr.parse::<SocketAddr>().is_err() {
+        return Err(invalid(format!("backend `{}`: expected an IP address and port", addr)));
+    }
+
+    let mut health = HealthCheckOverride::default();
+    let mut overridden = false;
+
+    for part in parts {
+        let (key, value) = part
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("backend {}: expected `key=value`, got `{}`", addr, part)))?;
+
+        match key {
+            "health_path" => health.path = Some(value.to_string()),
+            "health_method" => health.method = Some(value.to_string()),
+            "health_expect_status" => health.expect_status = Some(parse_number(key, value)?),
+            _ => return Err(invalid(format!("backend {}: unknown setting `{}`", addr, key))),
+        }
+        overridden = true;
+    }
+
+    Ok((addr, overridden.then_some(health)))
+}
+
+fn parse_number<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, IoError> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("invalid value `{}` for `{}`", value, key)))
+}
+
+/// Parses a comma-separated list naming each of `data`, `drain` and `admin`
+/// exactly once.
+fn parse_shutdown_order(value: &str) -> Result<Vec<ShutdownStep>, IoError> {
+    let mut order = Vec::new();
+
+    for name in value.split(',').map(str::trim) {
+        let step = match name {
+            "data" => ShutdownStep::StopData,
+            "drain" => ShutdownStep::Drain,
+            "admin" => ShutdownStep::StopAdmin,
+            _ => return Err(invalid(format!("unknown shutdown step `{}`", name))),
+        };
+        if order.contains(&step) {
+            return Err(invalid(format!("shutdown step `{}` listed twice", name)));
+        }
+        order.push(step);
+    }
+
+    if order.len() != 3 {
+        return Err(invalid("shutdown_order must list data, drain and admin".to_string()));
+    }
+    Ok(order)
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, IoError> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(invalid(format!("invalid value `{}` for `{}`, expected true or false", value, key))),
+    }
+}
+
+fn invalid(message: String) -> IoError {
+    IoError::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(method: &str, path: &str, expect_status: u16) -> HealthCheck {
+        HealthCheck {
+            method: method.to_string(),
+            path: path.to_string(),
+            expect_status,
+        }
+    }
+
+    #[test]
+    fn backends_use_their_own_health_paths() {
+        let config = Config::parse(
+            "backend = 127.0.0.1:9001 health_path=/ready\n\
+             backend = 127.0.0.1:9002 health_path=/status/live\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.backends, ["127.0.0.1:9001", "127.0.0.1:9002"]);
+        assert_eq!(config.health_check_for("127.0.0.1:9001"), check("GET", "/ready", 200));
+        assert_eq!(config.health_check_for("127.0.0.1:9002"), check("GET", "/status/live", 200));
+    }
+
+    #[test]
+    fn unset_backend_settings_fall_back_to_global_keys() {
+        let config = Config::parse(
+            "backend = 127.0.0.1:9001 health_expect_status=204\n\
+             backend = 127.0.0.1:9002\n\
+             health_path = /healthz\n\
+             health_method = HEAD\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.health_check_for("127.0.0.1:9001"), check("HEAD", "/healthz", 204));
+        assert_eq!(config.health_check_for("127.0.0.1:9002"), check("HEAD", "/healthz", 200));
+    }
+
+    #[test]
+    fn parse_backend_splits_address_and_settings() {
+        let (addr, health) = parse_backend("127.0.0.1:9001 health_method=OPTIONS health_path=/").unwrap();
+        let health = health.unwrap();
+
+        assert_eq!(addr, "127.0.0.1:9001");
+        assert_eq!(health.method.as_deref(), Some("OPTIONS"));
+        assert_eq!(health.path.as_deref(), Some("/"));
+        assert_eq!(health.expect_status, None);
+
+        let (_, health) = parse_backend("127.0.0.1:9001").unwrap();
+        assert!(health.is_none());
+    }
+
+    #[test]
+    fn parse_backend_rejects_bad_settings() {
+        for value in [
+            "127.0.0.1:9001 health_timeout=5",
+            "127.0.0.1:9001 health_path",
+            "127.0.0.1:9001 health_expect_status=ok",
+            "localhost:9001",
+            "127.0.0.1",
+            "",
+        ] {
+            let err = parse_backend(value).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", value);
+        }
+    }
+}
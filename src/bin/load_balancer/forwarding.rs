@@ -0,0 +1,68 @@
+use std::net::SocketAddr;
+
+use crate::config::ForwardedHeaders;
+use crate::http::{self, RequestHead};
+
+/// Adds the headers that tell the backend who the request came from, in the
+/// convention chosen by `forwarded_headers`. Existing values from upstream
+/// proxies are extended rather than replaced.
+pub fn add_forwarding_headers(
+    request: &[u8],
+    head: &RequestHead,
+    client: SocketAddr,
+    local: SocketAddr,
+    mode: &ForwardedHeaders
+) -> Vec<u8> {
+    if *mode == ForwardedHeaders::None {
+        return request.to_vec();
+    }
+
+    let host = head.header("Host").map(str::to_string);
+
+    http::edit_headers(request, |headers| {
+        if matches!(mode, ForwardedHeaders::XForwarded | ForwardedHeaders::Both) {
+            http::append_header(headers, "X-Forwarded-For", &client.ip().to_string());
+            http::set_header(headers, "X-Forwarded-Proto", "http");
+            if let Some(host) = &host {
+                http::set_header(headers, "X-Forwarded-Host", host);
+            }
+        }
+
+        if matches!(mode, ForwardedHeaders::Forwarded | ForwardedHeaders::Both) {
+            let mut element = format!(
+                "for={};by={};proto=http",
+                node(&client.ip().to_string(), client.ip().is_ipv6()),
+                node(&local.to_string(), local.is_ipv6())
+            );
+            if let Some(host) = &host {
+                element.push_str(";host=");
+                element.push_str(&quote(host));
+            }
+            http::append_header(headers, "Forwarded", &element);
+        }
+    })
+}
+
+/// Formats a `for`/`by` node. IPv6 addresses are bracketed, and since
+/// brackets and colons aren't token characters the result is quoted.
+fn node(value: &str, ipv6: bool) -> String {
+    if ipv6 && !value.starts_with('[') {
+        quote(&format!("[{}]", value))
+    } else {
+        quote(value)
+    }
+}
+
+/// Quotes a parameter value unless it is a plain token (RFC 7230 `tchar`s).
+fn quote(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
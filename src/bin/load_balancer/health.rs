@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, HealthCheck, HealthScheduler};
+
+/// Latest health check result for every backend. Backends start out healthy so
+/// traffic flows before the first round of checks has finished.
+pub struct HealthState {
+    status: Mutex<HashMap<String, bool>>,
+}
+
+impl HealthState {
+    pub fn new(servers: &[String]) -> Self {
+        HealthState {
+            status: Mutex::new(servers.iter().map(|s| (s.clone(), true)).collect()),
+        }
+    }
+
+    pub fn is_healthy(&self, server: &str) -> bool {
+        *self.status.lock().unwrap().get(server).unwrap_or(&true)
+    }
+
+    fn record(&self, server: &str, healthy: bool) {
+        let previous = self.status.lock().unwrap().insert(server.to_string(), healthy);
+        if previous != Some(healthy) {
+            println!("Server {} is now {}", server, if healthy { "healthy" } else { "unhealthy" });
+        }
+    }
+}
+
+/// Starts the background health checker selected in the config.
+pub fn spawn_health_checks(config: &Config, health: Arc<HealthState>) {
+    let interval = config.health_interval;
+
+    match config.health_scheduler {
+        HealthScheduler::Independent => {
+            for server in config.backends.clone() {
+                let check = config.health_check_for(&server);
+                let health = Arc::clone(&health);
+                thread::spawn(move || loop {
+                    health.record(&server, probe(&server, &check));
+                    thread::sleep(interval);
+                });
+            }
+        }
+        HealthScheduler::RatePaced { checks_per_second } => {
+            let servers = config
+                .backends
+                .iter()
+                .map(|server| (server.clone(), config.health_check_for(server)))
+                .collect();
+            thread::spawn(move || run_rate_paced(servers, interval, checks_per_second, health));
+        }
+    }
+}
+
+/// Token bucket holding at most one token, so probes are spaced evenly rather
+/// than sent in bursts.
+struct TokenBucket {
+    tokens: f64,
+    rate: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        TokenBucket {
+            tokens: 1.0,
+            rate,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Blocks until a token is available and consumes it.
+    fn take(&mut self) {
+        loop {
+            let now = Instant::now();
+            self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.rate).min(1.0);
+            self.last_refill = now;
+
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                return;
+            }
+
+            thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / self.rate));
+        }
+    }
+}
+
+fn run_rate_paced(servers: Vec<(String, HealthCheck)>, interval: Duration, checks_per_second: f64, health: Arc<HealthState>) {
+    if servers.is_empty() {
+        return;
+    }
+
+    // The interval target wins over the configured rate: if the fleet is too
+    // large to cover at that rate, pace just fast enough to reach everyone.
+    let minimum_rate = servers.len() as f64 / interval.as_secs_f64();
+    if checks_per_second < minimum_rate {
+        eprintln!(
+            "health_checks_per_second {} cannot cover {} servers every {:?}, using {:.2}",
+            checks_per_second, servers.len(), interval, minimum_rate
+        );
+    }
+    let mut bucket = TokenBucket::new(checks_per_second.max(minimum_rate));
+
+    // Stagger the first round so the schedule starts out evenly spread.
+    let start = Instant::now();
+    let mut next_due: Vec<Instant> = (0..servers.len())
+        .map(|i| start + interval.mul_f64(i as f64 / servers.len() as f64))
+        .collect();
+    let in_flight: Vec<Arc<AtomicBool>> = servers.iter().map(|_| Arc::new(AtomicBool::new(false))).collect();
+
+    loop {
+        let (index, due) = next_due
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by_key(|&(_, due)| due)
+            .unwrap();
+
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+        // Probes run on their own threads so the bucket paces when they
+        // start; a backend that hangs only delays its own result. One still
+        // running from the last round is left to finish rather than doubled.
+        if !in_flight[index].swap(true, Ordering::SeqCst) {
+            bucket.take();
+
+            let (server, check) = servers[index].clone();
+            let health = Arc::clone(&health);
+            let in_flight = Arc::clone(&in_flight[index]);
+            thread::spawn(move || {
+                health.record(&server, probe(&server, &check));
+                in_flight.store(false, Ordering::SeqCst);
+            });
+        }
+
+        // Anchor to the schedule rather than to when the probe finished, so
+        // slow probes don't push every later check back.
+        next_due[index] = due + interval;
+    }
+}
+
+fn probe(server: &str, check: &HealthCheck) -> bool {
+    let addr = match server.parse() {
+        Ok(addr) => addr,
+        Err(_) => return false,
+    };
+
+    match TcpStream::connect_timeout(&addr, Duration::from_secs(5)) {
+        Ok(mut stream) => check_stream(&mut stream, server, check),
+        Err(_) => false,
+    }
+}
+
+/// Sends `check` over `stream` and reports whether the backend answered with
+/// the expected status.
+fn check_stream(stream: &mut TcpStream, server: &str, check: &HealthCheck) -> bool {
+    if stream.set_write_timeout(Some(Duration::from_secs(5))).is_err()
+        || stream.set_read_timeout(Some(Duration::from_secs(5))).is_err()
+    {
+        return false;
+    }
+
+    let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n\r\n", check.method, check.path, server);
+    if stream.write_all(request.as_bytes()).is_err() {
+        return false;
+    }
+
+    let mut response = [0; 1024];
+    match stream.read(&mut response) {
+        Ok(size) if size > 0 => {
+            let response = String::from_utf8_lossy(&response[..size]);
+            let status = response
+                .lines()
+                .next()
+                .and_then(|line| line.split(' ').nth(1))
+                .and_then(|code| code.parse::<u16>().ok());
+            status == Some(check.expect_status)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Starts a backend that answers `status` to `request_line` and 404 to
+    /// anything else, for `connections` connections.
+    fn backend(request_line: &'static str, status: &'static str, connections: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            for stream in listener.incoming().take(connections) {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 1024];
+                let size = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..size]);
+                let reply = if request.starts_with(request_line) { status } else { "404 Not Found" };
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", reply);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    fn check(method: &str, path: &str, expect_status: u16) -> HealthCheck {
+        HealthCheck {
+            method: method.to_string(),
+            path: path.to_string(),
+            expect_status,
+        }
+    }
+
+    #[test]
+    fn probes_each_backend_with_its_own_check() {
+        let api = backend("GET /healthz HTTP/1.1", "200 OK", 2);
+        let static_files = backend("HEAD /status HTTP/1.1", "204 No Content", 2);
+        let api_check = check("GET", "/healthz", 200);
+        let static_check = check("HEAD", "/status", 204);
+
+        assert!(probe(&api, &api_check));
+        assert!(probe(&static_files, &static_check));
+
+        // Swapping the checks hits the wrong path, so both report unhealthy.
+        assert!(!probe(&api, &static_check));
+        assert!(!probe(&static_files, &api_check));
+    }
+}
@@ -0,0 +1,317 @@
+/// Offset just past the blank line that ends the head, if it is in the buffer.
+pub fn head_len(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+}
+
+/// Request line and headers of a client request.
+pub struct RequestHead {
+    pub method: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub len: usize,
+}
+
+impl RequestHead {
+    pub fn parse(buffer: &[u8]) -> Option<Self> {
+        let len = head_len(buffer)?;
+        let head = std::str::from_utf8(&buffer[..len]).ok()?;
+        let mut lines = head.split("\r\n");
+
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?.to_string();
+        let version = request_line.nth(1)?.to_string();
+
+        Some(RequestHead {
+            method,
+            version,
+            headers: parse_headers(lines),
+            len,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// Length of the body that follows the head. Requests without a
+    /// Content-Length have none. Any Transfer-Encoding, or a Content-Length
+    /// that is repeated or not a plain number, is refused: a backend might
+    /// read the body differently and take part of it for the next request.
+    pub fn content_length(&self) -> Result<usize, FramingError> {
+        if self.header("Transfer-Encoding").is_some() {
+            return Err(FramingError::TransferEncoding);
+        }
+
+        let mut values = self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            .map(|(_, value)| value);
+        match (values.next(), values.next()) {
+            (None, _) => Ok(0),
+            (Some(value), None) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                value.parse().map_err(|_| FramingError::InvalidContentLength)
+            }
+            _ => Err(FramingError::InvalidContentLength),
+        }
+    }
+
+    /// Whether the client expects the connection to stay open afterwards.
+    pub fn keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version != "HTTP/1.0",
+        }
+    }
+}
+
+/// Why the end of a request body can't be found safely.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FramingError {
+    TransferEncoding,
+    InvalidContentLength,
+}
+
+/// Number of requests, complete or not, waiting in a client's buffer.
+pub fn count_requests(buffer: &[u8]) -> usize {
+    let mut count = 0;
+    let mut rest = buffer;
+
+    while !rest.is_empty() {
+        count += 1;
+        let end = RequestHead::parse(rest).and_then(|head| Some(head.len + head.content_length().ok()?));
+        match end {
+            Some(end) if end <= rest.len() => rest = &rest[end..],
+            _ => break,
+        }
+    }
+
+    count
+}
+
+/// Status line and headers of a backend response.
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub len: usize,
+}
+
+impl ResponseHead {
+    pub fn parse(buffer: &[u8]) -> Option<Self> {
+        let len = head_len(buffer)?;
+        let head = std::str::from_utf8(&buffer[..len]).ok()?;
+        let mut lines = head.split("\r\n");
+
+        let mut status_line = lines.next()?.split(' ');
+        let version = status_line.next()?.to_string();
+        let status = status_line.next()?.parse::<u16>().ok()?;
+
+        Some(ResponseHead {
+            version,
+            status,
+            headers: parse_headers(lines),
+            len,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn content_length(&self) -> Option<usize> {
+        self.header("Content-Length")?.parse().ok()
+    }
+
+    /// Whether the backend leaves the connection open after this response.
+    pub fn keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version != "HTTP/1.0",
+        }
+    }
+
+    /// Whether the body uses chunked transfer coding.
+    pub fn is_chunked(&self) -> bool {
+        self.header("Transfer-Encoding").is_some_and(|value| {
+            value
+                .rsplit(',')
+                .next()
+                .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+        })
+    }
+}
+
+/// Where a backend response ends, judging by the part read so far.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseEnd {
+    /// The response is complete and ends at this offset.
+    At(usize),
+    /// The body runs until the backend closes the connection.
+    Close,
+    /// More has to be read to tell.
+    Incomplete,
+}
+
+/// Finds the end of the response at the start of `buffer` from its framing.
+/// Interim 1xx responses are passed over, so the end is that of the final
+/// response. Responses to HEAD requests never have a body.
+pub fn response_end(buffer: &[u8], head_request: bool) -> ResponseEnd {
+    let mut offset = 0;
+
+    loop {
+        let rest = &buffer[offset..];
+        let head = match ResponseHead::parse(rest) {
+            Some(head) => head,
+            // A complete head we can't make sense of has no usable framing.
+            None if head_len(rest).is_some() => return ResponseEnd::Close,
+            None => return ResponseEnd::Incomplete,
+        };
+        let body_start = offset + head.len;
+
+        return match head.status {
+            // After a protocol switch the connection is no longer HTTP.
+            101 => ResponseEnd::Close,
+            100..=199 => {
+                offset = body_start;
+                continue;
+            }
+            204 | 304 => ResponseEnd::At(body_start),
+            _ if head_request => ResponseEnd::At(body_start),
+            _ if head.is_chunked() => match chunked_len(&buffer[body_start..]) {
+                ResponseEnd::At(len) => ResponseEnd::At(body_start + len),
+                other => other,
+            },
+            _ if head.header("Transfer-Encoding").is_some() => ResponseEnd::Close,
+            _ => match head.content_length() {
+                Some(len) if buffer.len() >= body_start + len => ResponseEnd::At(body_start + len),
+                Some(_) => ResponseEnd::Incomplete,
+                None => ResponseEnd::Close,
+            },
+        };
+    }
+}
+
+/// Head of the final response in `buffer`, past any interim 1xx responses.
+pub fn final_head(buffer: &[u8]) -> Option<ResponseHead> {
+    let mut offset = 0;
+
+    loop {
+        let head = ResponseHead::parse(&buffer[offset..])?;
+        if !(100..200).contains(&head.status) || head.status == 101 {
+            return Some(head);
+        }
+        offset += head.len;
+    }
+}
+
+/// Length of a chunked body including the last chunk and trailers. Framing
+/// that doesn't parse is reported as running until the connection closes.
+fn chunked_len(body: &[u8]) -> ResponseEnd {
+    let line_end = |from: usize| body[from..].windows(2).position(|w| w == b"\r\n").map(|i| from + i);
+    let mut pos = 0;
+
+    loop {
+        let end = match line_end(pos) {
+            Some(end) => end,
+            None => return ResponseEnd::Incomplete,
+        };
+        let size = std::str::from_utf8(&body[pos..end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok());
+        let size = match size {
+            Some(size) => size,
+            None => return ResponseEnd::Close,
+        };
+        pos = end + 2;
+
+        if size == 0 {
+            // Trailers, if any, then the blank line closing the body.
+            loop {
+                match line_end(pos) {
+                    Some(end) if end == pos => return ResponseEnd::At(pos + 2),
+                    Some(end) => pos = end + 2,
+                    None => return ResponseEnd::Incomplete,
+                }
+            }
+        }
+
+        pos = match pos.checked_add(size) {
+            Some(pos) => pos,
+            None => return ResponseEnd::Close,
+        };
+        if body.len() < pos + 2 {
+            return ResponseEnd::Incomplete;
+        }
+        if &body[pos..pos + 2] != b"\r\n" {
+            return ResponseEnd::Close;
+        }
+        pos += 2;
+    }
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item = &'a str>) -> Vec<(String, String)> {
+    lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Rebuilds the head of a request or response with `edit` applied to its
+/// headers, keeping the start line and everything after the head as they were.
+pub fn edit_headers(request: &[u8], edit: impl FnOnce(&mut Vec<(String, String)>)) -> Vec<u8> {
+    let len = match head_len(request) {
+        Some(len) => len,
+        None => return request.to_vec(),
+    };
+    let head = match std::str::from_utf8(&request[..len]) {
+        Ok(head) => head,
+        Err(_) => return request.to_vec(),
+    };
+
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or("");
+    let mut headers = parse_headers(lines);
+    edit(&mut headers);
+
+    let mut result = Vec::with_capacity(request.len() + 128);
+    result.extend_from_slice(request_line.as_bytes());
+    result.extend_from_slice(b"\r\n");
+    for (name, value) in &headers {
+        result.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    result.extend_from_slice(b"\r\n");
+    result.extend_from_slice(&request[len..]);
+    result
+}
+
+/// Appends `value` to a comma-separated header, adding the header if absent.
+pub fn append_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    match headers.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        Some((_, existing)) => {
+            existing.push_str(", ");
+            existing.push_str(value);
+        }
+        None => headers.push((name.to_string(), value.to_string())),
+    }
+}
+
+/// Sets a header, replacing any existing values.
+pub fn set_header(headers: &mut Vec<(String, String)>, name: &str, value: &str) {
+    remove_header(headers, name);
+    headers.push((name.to_string(), value.to_string()));
+}
+
+/// Removes every value of a header.
+pub fn remove_header(headers: &mut Vec<(String, String)>, name: &str) {
+    headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+}
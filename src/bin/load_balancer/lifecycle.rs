@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// One step of the shutdown sequence. The steps run in the order given by
+/// `shutdown_order`, which lets the admin listener outlive the drain so its
+/// status stays visible until the end.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownStep {
+    /// Stop accepting new client connections on every data-plane listener.
+    StopData,
+    /// Wait for in-flight client connections to finish, up to the drain timeout.
+    Drain,
+    /// Close the admin listener.
+    StopAdmin,
+}
+
+/// Tracks open client connections and whether shutdown has begun.
+#[derive(Default)]
+pub struct Lifecycle {
+    shutdown_requested: Mutex<bool>,
+    shutdown_signal: Condvar,
+    draining: AtomicBool,
+    active: AtomicUsize,
+}
+
+impl Lifecycle {
+    /// Asks the main thread to run the shutdown sequence.
+    pub fn request_shutdown(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+        *self.shutdown_requested.lock().unwrap() = true;
+        self.shutdown_signal.notify_all();
+    }
+
+    pub fn wait_for_shutdown(&self) {
+        let mut requested = self.shutdown_requested.lock().unwrap();
+        while !*requested {
+            requested = self.shutdown_signal.wait(requested).unwrap();
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Counts a client connection as open until the guard is dropped.
+    pub fn track_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard { lifecycle: Arc::clone(self) }
+    }
+
+    /// Waits for every client connection to close. Returns `false` if some
+    /// were still open when the timeout ran out.
+    pub fn wait_for_drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.active_connections() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        true
+    }
+}
+
+pub struct ConnectionGuard {
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.lifecycle.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,53 @@
+use std::io::Error as IoError;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// A listener accepting connections on its own thread until stopped.
+pub struct AcceptLoop {
+    addr: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl AcceptLoop {
+    pub fn spawn<F>(addr: &str, handle: F) -> Result<Self, IoError>
+    where
+        F: Fn(TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let stop_flag = Arc::clone(&stopping);
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop_flag.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => handle(stream),
+                    Err(e) => eprintln!("Error accepting connection on {}: {:?}", addr, e),
+                }
+            }
+        });
+
+        Ok(AcceptLoop { addr, stopping, thread })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting and closes the listening socket. Connections that were
+    /// already accepted are left to finish.
+    pub fn stop(self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        // `accept` can't be interrupted, so wake it with a connection of our
+        // own; the loop sees the flag and exits without handling it.
+        let _ = TcpStream::connect(self.addr);
+        let _ = self.thread.join();
+        println!("Stopped listening on {}", self.addr);
+    }
+}
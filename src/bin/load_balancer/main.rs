@@ -0,0 +1,964 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{Shutdown, TcpStream};
+use std::io::{Read, Write, Error as IoError};
+use std::thread;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+mod admin;
+mod config;
+mod forwarding;
+mod health;
+mod http;
+mod lifecycle;
+mod listener;
+mod metrics;
+mod pool;
+mod probe;
+mod rewrite;
+mod trace;
+mod workers;
+
+use config::{Config, EarlyResponse, ErrorFormat, PoolSizing, StickySessions};
+use health::HealthState;
+use http::{FramingError, RequestHead, ResponseEnd};
+use lifecycle::{ConnectionGuard, Lifecycle, ShutdownStep};
+use listener::AcceptLoop;
+use metrics::Metrics;
+use pool::{ConnectionPool, PooledStream};
+use trace::TraceContext;
+use workers::WorkerPool;
+
+/// Requests whose head doesn't fit in this many bytes are rejected.
+const MAX_HEAD_LEN: usize = 64 * 1024;
+
+/// Methods a request can be sent again with, RFC 9110 section 9.2.2.
+const IDEMPOTENT_METHODS: &[&str] = &["GET", "HEAD", "PUT", "DELETE", "OPTIONS", "TRACE"];
+
+/// How long the body upload gets to finish once the response has been passed
+/// on before the response is treated as early.
+const UPLOAD_GRACE: Duration = Duration::from_millis(100);
+
+/// State shared by every connection thread.
+#[derive(Clone)]
+struct Shared {
+    servers: Arc<Mutex<Vec<String>>>,
+    counter: Arc<Mutex<usize>>,
+    pool: Arc<Mutex<ConnectionPool>>,
+    health: Arc<HealthState>,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    lifecycle: Arc<Lifecycle>,
+}
+
+fn main() -> Result<(), IoError> {
+    let config = match std::env::args().nth(1) {
+        Some(path) => Config::load(&path)?,
+        None => Config::default(),
+    };
+
+    let lifecycle = Arc::new(Lifecycle::default());
+    let metrics = Arc::new(Metrics::new(config.selection_window, config.metrics_exemplars));
+    let admin = admin::spawn_admin(
+        &config.admin_listen,
+        Arc::clone(&metrics),
+        Arc::clone(&lifecycle),
+        config.admin_shutdown,
+    )?;
+    if config.log_selection_share {
+        spawn_selection_log(config.selection_window, Arc::clone(&metrics));
+    }
+
+    let health = Arc::new(HealthState::new(&config.backends));
+    health::spawn_health_checks(&config, Arc::clone(&health));
+
+    let shared = Shared {
+        servers: Arc::new(Mutex::new(config.backends.clone())),
+        counter: Arc::new(Mutex::new(0)),
+        pool: Arc::new(Mutex::new(ConnectionPool::new(&config))),
+        health,
+        config: Arc::new(config),
+        metrics,
+        lifecycle: Arc::clone(&lifecycle),
+    };
+
+    if let PoolSizing::Adaptive { adjust_interval, .. } = shared.config.pool_sizing {
+        let pool = Arc::clone(&shared.pool);
+        thread::spawn(move || loop {
+            thread::sleep(adjust_interval);
+            let shortfall = {
+                let mut pool = pool.lock().unwrap();
+                pool.adjust_sizes();
+                pool.idle_shortfall()
+            };
+
+            // Connect without holding the pool lock, so requests aren't held up.
+            for (server, missing) in shortfall {
+                for _ in 0..missing {
+                    match pool::connect(&server) {
+                        Ok(stream) => pool.lock().unwrap().add_idle(&server, stream),
+                        Err(e) => {
+                            eprintln!("Failed to open idle connection to {}: {:?}", server, e);
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    let workers = shared.config.workers.map(|workers| {
+        let shared = shared.clone();
+        WorkerPool::spawn(
+            workers,
+            shared.config.accept_queue_size,
+            shared.config.accept_queue_policy,
+            Arc::clone(&shared.metrics),
+            move |stream, guard| serve_connection(stream, guard, shared.clone()),
+        )
+    });
+
+    let mut listeners = Vec::new();
+    for addr in &shared.config.listen {
+        let shared = shared.clone();
+        let workers = workers.clone();
+        let listener = AcceptLoop::spawn(addr, move |stream| {
+            // Counted from here on, so the drain also waits for connections
+            // still queued for a worker.
+            let guard = shared.lifecycle.track_connection();
+            match &workers {
+                Some(workers) => workers.submit(stream, guard),
+                None => {
+                    let shared = shared.clone();
+                    thread::spawn(move || serve_connection(stream, guard, shared));
+                }
+            }
+        })?;
+        println!("Load balancer listening on {}", listener.addr());
+        listeners.push(listener);
+    }
+
+    lifecycle.wait_for_shutdown();
+    println!("Shutting down");
+
+    let mut admin = Some(admin);
+    for step in &shared.config.shutdown_order {
+        match step {
+            ShutdownStep::StopData => listeners.drain(..).for_each(AcceptLoop::stop),
+            ShutdownStep::Drain => {
+                if !lifecycle.wait_for_drain(shared.config.drain_timeout) {
+                    eprintln!(
+                        "Drain timed out with {} connections still open",
+                        lifecycle.active_connections()
+                    );
+                }
+            }
+            ShutdownStep::StopAdmin => {
+                if let Some(admin) = admin.take() {
+                    admin.stop();
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `handle_connection`. The connection counts as open for the drain
+/// until `guard` is dropped at the end.
+fn serve_connection(stream: TcpStream, _guard: ConnectionGuard, shared: Shared) {
+    if let Err(e) = handle_connection(stream, shared) {
+        eprintln!("Error handling connection: {:?}", e);
+    }
+}
+
+/// Prints each backend's share of the traffic once per selection window.
+fn spawn_selection_log(window: Duration, metrics: Arc<Metrics>) {
+    thread::spawn(move || loop {
+        thread::sleep(window);
+
+        let shares: Vec<String> = metrics
+            .selection_shares()
+            .iter()
+            .map(|(backend, share)| format!("{} {:.1}%", backend, share * 100.0))
+            .collect();
+        if !shares.is_empty() {
+            println!("Backend share over the last {:?}: {}", window, shares.join(", "));
+        }
+    });
+}
+
+/// Serves requests from one client connection until it closes, stops asking
+/// for keep-alive, or runs into one of the per-connection limits.
+fn handle_connection(mut client_stream: TcpStream, shared: Shared) -> Result<(), IoError> {
+    client_stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut pending = Vec::new();
+    let mut served = 0;
+
+    loop {
+        let (head, request) = match read_request(&mut client_stream, &mut pending) {
+            Ok(Some(ClientRequest::Framed(head, request))) => (head, request),
+            Ok(Some(ClientRequest::Unframed { status, code, message })) => {
+                // Where this request ends, and so where the next one starts,
+                // can't be trusted, so nothing more is read from the client.
+                return send_error_response(
+                    &mut client_stream,
+                    &shared.config.error_format,
+                    status,
+                    code,
+                    &[("Connection", "close")],
+                    message,
+                );
+            }
+            Ok(None) if served == 0 => {
+                return Err(IoError::new(std::io::ErrorKind::UnexpectedEof, "Client closed connection"));
+            }
+            Ok(None) => return Ok(()),
+            // An idle keep-alive connection timing out is a normal way to end.
+            Err(e) if served > 0 && pending.is_empty() && is_timeout(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        // Whatever is still buffered was pipelined behind this request. When
+        // the request has been read in full, anything already waiting on the
+        // socket is pulled in too, so the count isn't limited to one read.
+        if request.len() == head.len + head.content_length().unwrap_or(0) {
+            read_ahead(&client_stream, &mut pending, shared.config.max_pipelined_requests)?;
+        }
+        let outstanding = 1 + http::count_requests(&pending);
+        if outstanding > shared.config.max_pipelined_requests {
+            Metrics::increment(&shared.metrics.pipelined_limit_exceeded);
+            return send_error_response(
+                &mut client_stream,
+                &shared.config.error_format,
+                "429 Too Many Requests",
+                "too_many_requests",
+                &[("Connection", "close")],
+                "Too many pipelined requests on this connection",
+            );
+        }
+
+        let started = Instant::now();
+        let trace = shared.config.trace_propagation.then(|| trace::trace_context(&head));
+
+        let reusable = proxy_request(&mut client_stream, &head, &request, trace.as_ref(), &shared)?;
+        served += 1;
+        shared.metrics.observe_duration(started.elapsed(), trace.as_ref().map(|t| t.trace_id.as_str()));
+
+        // While draining, finish the current request but take no more.
+        if !reusable || !head.keep_alive() || shared.lifecycle.is_draining() {
+            return Ok(());
+        }
+        if served >= shared.config.max_requests_per_connection {
+            Metrics::increment(&shared.metrics.request_limit_reached);
+            return Ok(());
+        }
+    }
+}
+
+/// A request head read from a client.
+enum ClientRequest {
+    /// The head and whatever part of the body has already arrived.
+    Framed(RequestHead, Vec<u8>),
+    /// A request whose end can't be found, to be answered with this error.
+    Unframed {
+        status: &'static str,
+        code: &'static str,
+        message: &'static str,
+    },
+}
+
+/// Reads until `pending` holds a complete request head and splits it off
+/// together with whatever part of the body has already arrived. The rest of
+/// the body is left on the socket so it can be streamed to the backend.
+/// Returns `None` if the client closes the connection between requests.
+fn read_request(client_stream: &mut TcpStream, pending: &mut Vec<u8>) -> Result<Option<ClientRequest>, IoError> {
+    let mut buffer = [0; 1024];
+
+    loop {
+        if http::head_len(pending).is_some() {
+            let head = match RequestHead::parse(pending) {
+                Some(head) => head,
+                None => {
+                    return Ok(Some(ClientRequest::Unframed {
+                        status: "400 Bad Request",
+                        code: "bad_request",
+                        message: "Malformed request head",
+                    }));
+                }
+            };
+            let body_len = match head.content_length() {
+                Ok(body_len) => body_len,
+                Err(FramingError::TransferEncoding) => {
+                    return Ok(Some(ClientRequest::Unframed {
+                        status: "501 Not Implemented",
+                        code: "unsupported_transfer_encoding",
+                        message: "Transfer-Encoding is not supported on requests",
+                    }));
+                }
+                Err(FramingError::InvalidContentLength) => {
+                    return Ok(Some(ClientRequest::Unframed {
+                        status: "400 Bad Request",
+                        code: "bad_request",
+                        message: "Invalid Content-Length",
+                    }));
+                }
+            };
+
+            let available = pending.len().min(head.len + body_len);
+            let request = pending.drain(..available).collect();
+            return Ok(Some(ClientRequest::Framed(head, request)));
+        } else if pending.len() > MAX_HEAD_LEN {
+            return Ok(Some(ClientRequest::Unframed {
+                status: "431 Request Header Fields Too Large",
+                code: "head_too_large",
+                message: "Request head too large",
+            }));
+        }
+
+        let bytes_read = client_stream.read(&mut buffer)?;
+        if bytes_read == 0 {
+            if pending.is_empty() {
+                return Ok(None);
+            }
+            return Err(IoError::new(std::io::ErrorKind::UnexpectedEof, "Client closed connection mid-request"));
+        }
+        pending.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+/// Moves whatever the client has already sent into `pending` without waiting
+/// for more, until it holds `wanted` complete requests or a head's worth of
+/// bytes.
+fn read_ahead(mut client_stream: &TcpStream, pending: &mut Vec<u8>, wanted: usize) -> Result<(), IoError> {
+    let mut buffer = [0; 8192];
+    client_stream.set_nonblocking(true)?;
+
+    let result = loop {
+        if pending.len() >= MAX_HEAD_LEN || http::count_requests(pending) >= wanted {
+            break Ok(());
+        }
+        match client_stream.read(&mut buffer) {
+            // A close is left for `read_request` to notice once `pending` is
+            // used up.
+            Ok(0) => break Ok(()),
+            Ok(bytes_read) => pending.extend_from_slice(&buffer[..bytes_read]),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+
+    client_stream.set_nonblocking(false)?;
+    result
+}
+
+fn is_timeout(e: &IoError) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+fn is_connection_lost(e: &IoError) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Forwards one request and its response. `request` holds the head and the
+/// part of the body read so far; the rest is copied from the client on its own
+/// thread while the response is read, so a backend that answers before taking
+/// the whole upload doesn't leave both sides waiting on each other.
+///
+/// Returns whether the client connection can carry another request.
+fn proxy_request(
+    client_stream: &mut TcpStream,
+    head: &RequestHead,
+    request: &[u8],
+    trace: Option<&TraceContext>,
+    shared: &Shared
+) -> Result<bool, IoError> {
+    let Shared { servers, counter, pool, health, config, metrics, .. } = shared;
+
+    // TRACE is refused here rather than forwarded so backends never echo
+    // client headers back; see `Config::allow_trace`.
+    if !config.allow_trace && head.method == "TRACE" {
+        send_error_response(
+            client_stream,
+            &config.error_format,
+            "405 Method Not Allowed",
+            "method_not_allowed",
+            &[("Allow", "GET, HEAD, POST, PUT, DELETE, OPTIONS, PATCH"), ("Connection", "close")],
+            "TRACE is disabled",
+        )?;
+        return Ok(false);
+    }
+
+    let sticky_key = match config.sticky_sessions {
+        StickySessions::Off => None,
+        StickySessions::ClientIp => {
+            let mut hasher = DefaultHasher::new();
+            client_stream.peer_addr()?.ip().hash(&mut hasher);
+            Some(hasher.finish())
+        }
+    };
+    let server = find_available_server(servers, counter, pool, health, sticky_key, config.sticky_max_in_use, metrics);
+
+    let (server_addr, mut server_stream) = match server {
+        Some(server) => server,
+        None => {
+            send_error_response(
+                client_stream,
+                &config.error_format,
+                "503 Service Unavailable",
+                "no_backends",
+                &[],
+                "All servers are currently unavailable",
+            )?;
+            return Ok(false);
+        }
+    };
+    metrics.record_selection(&server_addr);
+
+    // `read_request` only hands over requests whose length is known.
+    let remaining = head.len + head.content_length().unwrap_or(0) - request.len();
+    let request = forwarding::add_forwarding_headers(
+        request,
+        head,
+        client_stream.peer_addr()?,
+        client_stream.local_addr()?,
+        &config.forwarded_headers,
+    );
+    let request = match trace.and_then(|t| t.inject.as_deref()) {
+        Some(traceparent) => http::edit_headers(&request, |headers| {
+            http::set_header(headers, trace::TRACEPARENT, traceparent);
+        }),
+        None => request,
+    };
+    let token = probe::next_token();
+    let request = if config.probe_connections {
+        if probe::has_stale_data(&server_stream) {
+            eprintln!("Ejecting connection to {}: unread data before request {}", server_addr, token);
+            pool.lock().unwrap().discard_connection(&server_addr, server_stream);
+            server_stream = match checkout_again(client_stream, pool, &server_addr, &config.error_format)? {
+                Some(stream) => stream,
+                None => return Ok(false),
+            };
+        }
+        // Replaces any probe header the client sent, so the backend can only
+        // echo ours.
+        http::edit_headers(&request, |headers| {
+            http::set_header(headers, probe::PROBE_HEADER, &token);
+        })
+    } else {
+        request
+    };
+    client_stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let drain = config.early_response == EarlyResponse::Drain;
+
+    let (forwarded, upload, early) = loop {
+        // The backend may have closed a pooled connection just before it was
+        // handed out, so it fails without a response. A request that is safe
+        // to send twice and has been read in full is then sent again on
+        // another connection.
+        let retryable = server_stream.is_reused() && remaining == 0 && IDEMPOTENT_METHODS.contains(&head.method.as_str());
+
+        server_stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+        let sent = server_stream.write_all(&request);
+        if sent.is_err() && retryable {
+            pool.lock().unwrap().discard_connection(&server_addr, server_stream);
+            server_stream = match checkout_again(client_stream, pool, &server_addr, &config.error_format)? {
+                Some(stream) => stream,
+                None => return Ok(false),
+            };
+            continue;
+        }
+        sent?;
+        server_stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+        let client: &TcpStream = client_stream;
+        let server_ref: &TcpStream = &server_stream;
+
+        let result = thread::scope(|scope| {
+            let (done, uploaded) = mpsc::channel();
+            let uploader = scope.spawn(move || {
+                let upload = upload_body(client, server_ref, remaining, drain);
+                let _ = done.send(());
+                upload
+            });
+
+            let forwarded = forward_response(client, server_ref, &server_addr, &token, head, retryable, shared);
+
+            // A backend usually answers once it has the whole body, and the
+            // uploader is then about to return. Only one still busy after a
+            // grace period is stuck behind an early response.
+            let early = uploaded.recv_timeout(UPLOAD_GRACE).is_err();
+            if early {
+                // The backend answered before reading the whole body. Either
+                // unblock the uploader by closing the client, or let it read out
+                // the rest of the body once the backend stops taking it.
+                match config.early_response {
+                    EarlyResponse::Close => {
+                        let _ = client.shutdown(Shutdown::Both);
+                    }
+                    EarlyResponse::Drain => {
+                        let _ = server_ref.shutdown(Shutdown::Both);
+                    }
+                }
+            }
+
+            (forwarded, uploader.join().unwrap(), early)
+        });
+
+        if let Ok(Forwarded::Unanswered) = result.0 {
+            eprintln!("Connection to {} closed before answering, retrying on another", server_addr);
+            pool.lock().unwrap().discard_connection(&server_addr, server_stream);
+            server_stream = match checkout_again(client_stream, pool, &server_addr, &config.error_format)? {
+                Some(stream) => stream,
+                None => return Ok(false),
+            };
+            continue;
+        }
+        break result;
+    };
+
+    if early && !drain {
+        // The uploader was cut off on purpose, so its error is expected.
+        pool.lock().unwrap().discard_connection(&server_addr, server_stream);
+        return forwarded.map(|_| false);
+    }
+
+    let body_sent = match upload {
+        Ok(body_sent) => body_sent,
+        Err(e) => {
+            pool.lock().unwrap().discard_connection(&server_addr, server_stream);
+            forwarded?;
+            return Err(e);
+        }
+    };
+
+    match forwarded {
+        Ok(Forwarded::Delimited { backend_reusable }) => {
+            // The backend connection sits at the start of the next response
+            // only if it took the whole request and sent nothing extra. One
+            // shut down to stop an early upload is never reused.
+            if body_sent && backend_reusable && !early {
+                pool.lock().unwrap().release_connection(&server_addr, server_stream);
+            } else {
+                pool.lock().unwrap().discard_connection(&server_addr, server_stream);
+            }
+            Ok(body_sent || drain)
+        }
+        Ok(Forwarded::CloseDelimited) => {
+            // The backend ended the body by closing, and the client only sees
+            // the end of it when we close too.
+            pool.lock().unwrap().discard_connection(&server_addr, server_stream);
+            Ok(false)
+        }
+        Ok(_) => {
+            // Part of this request never reached the backend, or its response
+            // was rejected, so the connection can't be trusted for the next one.
+            // The client connection is still usable if its body was drained.
+            pool.lock().unwrap().discard_connection(&server_addr, server_stream);
+            Ok(body_sent || drain)
+        }
+        Err(e) => {
+            pool.lock().unwrap().discard_connection(&server_addr, server_stream);
+            Err(e)
+        }
+    }
+}
+
+/// Checks out a fresh connection to `server_addr` after the last one had to be
+/// given up. If there is none to be had the client is sent an error instead,
+/// and `None` is returned.
+fn checkout_again(
+    client_stream: &mut TcpStream,
+    pool: &Mutex<ConnectionPool>,
+    server_addr: &str,
+    format: &ErrorFormat
+) -> Result<Option<PooledStream>, IoError> {
+    let checkout = pool.lock().unwrap().get_connection(server_addr);
+    let e = match checkout {
+        Ok(stream) => return Ok(Some(stream)),
+        Err(e) => e,
+    };
+
+    eprintln!("Failed to connect to server {}: {:?}", server_addr, e);
+    let (status, code, message) = if e.kind() == std::io::ErrorKind::WouldBlock {
+        ("503 Service Unavailable", "connection_limit", "The server has no free connections")
+    } else {
+        ("502 Bad Gateway", "backend_unreachable", "The server could not be reached")
+    };
+    send_error_response(client_stream, format, status, code, &[("Connection", "close")], message)?;
+    Ok(None)
+}
+
+/// Copies the rest of a request body from the client to the backend. If the
+/// backend stops accepting it and `drain` is set, the remainder is still read
+/// from the client and dropped, so the connection is left at the start of the
+/// next request. Returns whether the whole body reached the backend.
+fn upload_body(
+    mut client: &TcpStream,
+    mut server: &TcpStream,
+    mut remaining: usize,
+    drain: bool
+) -> Result<bool, IoError> {
+    let mut buffer = [0; 8192];
+    let mut forwarding = true;
+
+    while remaining > 0 {
+        let bytes_read = client.read(&mut buffer[..remaining.min(8192)])?;
+        if bytes_read == 0 {
+            return Err(IoError::new(std::io::ErrorKind::UnexpectedEof, "Client closed connection mid-body"));
+        }
+        remaining -= bytes_read;
+
+        if forwarding && server.write_all(&buffer[..bytes_read]).is_err() {
+            if !drain {
+                return Ok(false);
+            }
+            forwarding = false;
+        }
+    }
+
+    Ok(forwarding)
+}
+
+/// How a backend response was handled.
+enum Forwarded {
+    /// Passed on, and the client can find its end without the connection
+    /// closing. The backend connection can be reused if the backend keeps it
+    /// open and sent nothing past the end of the response.
+    Delimited { backend_reusable: bool },
+    /// Passed on, but the body runs until the connection closes.
+    CloseDelimited,
+    /// Replaced with an error, because the backend timed out, sent nothing or
+    /// only part of a response, or the response failed the probe check.
+    Rejected,
+    /// Nothing came back and nothing was sent to the client, so the request
+    /// can be retried.
+    Unanswered,
+}
+
+/// Reads one response from the backend, stopping where its framing says it
+/// ends rather than waiting for the backend to close the connection. Stops
+/// early only if the connection closes, in which case the end is `Close` for
+/// a response that runs until then and `Incomplete` for one cut short. A
+/// connection reset counts as cutting the response short, and one before
+/// anything arrived as closing without a response.
+fn read_response(mut server: &TcpStream, head_request: bool) -> Result<(Vec<u8>, ResponseEnd), IoError> {
+    let mut response = Vec::new();
+    let mut buffer = [0; 8192];
+
+    loop {
+        let end = http::response_end(&response, head_request);
+        if let ResponseEnd::At(_) = end {
+            return Ok((response, end));
+        }
+
+        let bytes_read = match server.read(&mut buffer) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) if is_connection_lost(&e) => return Ok((response, ResponseEnd::Incomplete)),
+            Err(e) => return Err(e),
+        };
+        if bytes_read == 0 {
+            return Ok((response, end));
+        }
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+/// Reads the backend response to `request` and passes it to the client.
+fn forward_response(
+    mut client: &TcpStream,
+    server: &TcpStream,
+    server_addr: &str,
+    token: &str,
+    request: &RequestHead,
+    retryable: bool,
+    shared: &Shared
+) -> Result<Forwarded, IoError> {
+    let format = &shared.config.error_format;
+
+    let (mut response, end) = match read_response(server, request.method == "HEAD") {
+        Ok(response) => response,
+        Err(e) if is_timeout(&e) => {
+            // A complete response would already have been returned, so
+            // nothing has been sent to the client yet and it can still get a
+            // proper error instead of a dropped connection.
+            eprintln!("Timed out waiting for a response from {}", server_addr);
+            let response = error_response(
+                format,
+                "504 Gateway Timeout",
+                "backend_timeout",
+                &[],
+                "The server took too long to respond",
+            );
+            client.write_all(response.as_bytes())?;
+            client.flush()?;
+            return Ok(Forwarded::Rejected);
+        }
+        Err(e) => return Err(e),
+    };
+
+    if response.is_empty() && retryable {
+        return Ok(Forwarded::Unanswered);
+    }
+    if response.is_empty() {
+        eprintln!("Empty response from {}", server_addr);
+        let response = error_response(
+            format,
+            "502 Bad Gateway",
+            "empty_response",
+            &[],
+            "The server closed the connection without responding",
+        );
+        client.write_all(response.as_bytes())?;
+        client.flush()?;
+        return Ok(Forwarded::Rejected);
+    }
+
+    if end == ResponseEnd::Incomplete {
+        eprintln!("Incomplete response from {}", server_addr);
+        let response = error_response(
+            format,
+            "502 Bad Gateway",
+            "incomplete_response",
+            &[],
+            "The server closed the connection in the middle of its response",
+        );
+        client.write_all(response.as_bytes())?;
+        client.flush()?;
+        return Ok(Forwarded::Rejected);
+    }
+
+    if shared.config.probe_connections {
+        // Checked before anything past the end is cut off, since extra bytes
+        // are a sign of a misrouted response.
+        if let Err(reason) = probe::verify_response(&response, token) {
+            // The response may belong to another client, so it is
+            // not passed on.
+            eprintln!("Ejecting connection to {}: {}", server_addr, reason);
+            let response = error_response(
+                format,
+                "502 Bad Gateway",
+                "probe_mismatch",
+                &[],
+                "Backend response did not match the request",
+            );
+            client.write_all(response.as_bytes())?;
+            client.flush()?;
+            return Ok(Forwarded::Rejected);
+        }
+    }
+
+    let backend_reusable = match end {
+        ResponseEnd::At(len) => {
+            let reusable = response.len() == len
+                && http::final_head(&response).is_some_and(|head| head.keep_alive());
+            response.truncate(len);
+            reusable
+        }
+        _ => false,
+    };
+
+    // The token identifies this balancer's requests and is none of the
+    // client's business.
+    let response = if shared.config.probe_connections {
+        probe::strip_header(response)
+    } else {
+        response
+    };
+
+    let response = match request.header("Host") {
+        Some(external_host) if !shared.config.html_rewrite_hosts.is_empty() => rewrite::rewrite_html(
+            response,
+            &shared.config.html_rewrite_hosts,
+            external_host,
+            shared.config.html_rewrite_max_bytes,
+        ),
+        _ => response,
+    };
+
+    client.write_all(&response)?;
+    client.flush()?;
+
+    match end {
+        ResponseEnd::At(_) => Ok(Forwarded::Delimited { backend_reusable }),
+        _ => Ok(Forwarded::CloseDelimited),
+    }
+}
+
+/// Picks the next healthy server in round-robin order along with a connection
+/// to it, skipping servers that are down or at their connection limit.
+///
+/// With a `sticky_key` the server it hashes to is tried first. If that server
+/// has more than `sticky_max_in_use` connections checked out, the request
+/// falls back to round-robin so one hot client can't pile onto a single
+/// backend; it goes back to its sticky server once the load there drops.
+/// The round-robin pass skips the overloaded server unless it is the only
+/// healthy one.
+fn find_available_server(
+    servers: &Arc<Mutex<Vec<String>>>,
+    counter: &Arc<Mutex<usize>>,
+    pool: &Arc<Mutex<ConnectionPool>>,
+    health: &HealthState,
+    sticky_key: Option<u64>,
+    sticky_max_in_use: Option<usize>,
+    metrics: &Metrics
+) -> Option<(String, PooledStream)> {
+    let servers = servers.lock().unwrap();
+    let mut counter = counter.lock().unwrap();
+    let mut pool = pool.lock().unwrap();
+    let mut overloaded = None;
+
+    if let Some(key) = sticky_key {
+        let server = &servers[(key % servers.len() as u64) as usize];
+
+        if health.is_healthy(server) {
+            if sticky_max_in_use.is_some_and(|limit| pool.in_use(server) > limit) {
+                Metrics::increment(&metrics.sticky_overrides);
+                overloaded = Some(server);
+            } else {
+                match pool.get_connection(server) {
+                    Ok(stream) => return Some((server.clone(), stream)),
+                    Err(e) => eprintln!("Failed to connect to server {}: {:?}", server, e),
+                }
+            }
+        }
+    }
+
+    let skip = overloaded.filter(|overloaded| {
+        servers.iter().any(|server| server != *overloaded && health.is_healthy(server))
+    });
+    let start_index = *counter % servers.len();
+
+    for i in 0..servers.len() {
+        let index = (start_index + i) % servers.len();
+        let server = &servers[index];
+
+        if !health.is_healthy(server) || skip == Some(server) {
+            continue;
+        }
+
+        match pool.get_connection(server) {
+            Ok(stream) => {
+                *counter = index + 1;
+                return Some((server.clone(), stream));
+            }
+            Err(e) => {
+                eprintln!("Failed to connect to server {}: {:?}", server, e);
+            }
+        }
+    }
+
+    None
+}
+
+fn send_error_response(
+    client_stream: &mut TcpStream,
+    format: &ErrorFormat,
+    status: &str,
+    code: &str,
+    headers: &[(&str, &str)],
+    message: &str
+) -> Result<(), IoError> {
+    let response = error_response(format, status, code, headers, message);
+    client_stream.write_all(response.as_bytes())?;
+    client_stream.flush()?;
+    Ok(())
+}
+
+/// Builds a response for an error raised by the balancer itself. `code` is a
+/// short machine-readable name for the error, only included in JSON bodies.
+fn error_response(
+    format: &ErrorFormat,
+    status: &str,
+    code: &str,
+    headers: &[(&str, &str)],
+    message: &str
+) -> String {
+    let (content_type, body) = match format {
+        ErrorFormat::Plain => ("text/plain", message.to_string()),
+        ErrorFormat::Json => (
+            "application/json",
+            format!("{{\"error\":{},\"message\":{}}}", json_string(code), json_string(message)),
+        ),
+    };
+
+    let extra_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}\r\n{}",
+        status, content_type, body.len(), extra_headers, body
+    )
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn shared(config: Config) -> Shared {
+        Shared {
+            servers: Arc::new(Mutex::new(config.backends.clone())),
+            counter: Arc::new(Mutex::new(0)),
+            pool: Arc::new(Mutex::new(ConnectionPool::new(&config))),
+            health: Arc::new(HealthState::new(&config.backends)),
+            metrics: Arc::new(Metrics::new(config.selection_window, false)),
+            config: Arc::new(config),
+            lifecycle: Arc::new(Lifecycle::default()),
+        }
+    }
+
+    #[test]
+    fn too_many_pipelined_requests_get_429() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut config = Config::default();
+        config.backends = Vec::new();
+        let shared = shared(config);
+
+        // Well over the 1 KiB read size all together, as a browser's would be.
+        let request = "GET /assets/app.js HTTP/1.1\r\n\
+            Host: shop.example.com\r\n\
+            User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0\r\n\
+            Accept: */*\r\n\
+            Accept-Language: en-GB,en;q=0.5\r\n\
+            Accept-Encoding: gzip, deflate, br\r\n\
+            Referer: https://shop.example.com/basket\r\n\
+            Cookie: session=4f3c2a1b9e8d7c6b5a49382716059483; theme=dark\r\n\
+            Connection: keep-alive\r\n\r\n";
+        let pipelined = request.repeat(shared.config.max_pipelined_requests + 1);
+        client.write_all(pipelined.as_bytes()).unwrap();
+
+        handle_connection(stream, shared.clone()).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 429 "), "got {:?}", response);
+        assert_eq!(shared.metrics.pipelined_limit_exceeded.load(std::sync::atomic::Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The selection window is tracked in this many slots. Requests age out one
+/// slot at a time, so shares can lag the window by up to one slot.
+const WINDOW_SLOTS: u32 = 10;
+
+/// Upper bounds, in seconds, of the request duration histogram buckets. A
+/// final `+Inf` bucket catches everything slower.
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Metrics exported on the admin listener, in Prometheus text format or, for
+/// scrapers that ask for it, OpenMetrics.
+pub struct Metrics {
+    pub pipelined_limit_exceeded: AtomicU64,
+    pub request_limit_reached: AtomicU64,
+    pub sticky_overrides: AtomicU64,
+    pub accept_queue_evicted: AtomicU64,
+    pub accept_queue_rejected: AtomicU64,
+    backend_requests: Mutex<HashMap<String, u64>>,
+    selection: Mutex<SelectionWindow>,
+    request_duration: Mutex<Histogram>,
+    exemplars: bool,
+}
+
+impl Metrics {
+    /// With `exemplars` set, each duration bucket remembers the trace ID of
+    /// its latest request and OpenMetrics output includes it.
+    pub fn new(selection_window: Duration, exemplars: bool) -> Self {
+        Metrics {
+            pipelined_limit_exceeded: AtomicU64::new(0),
+            request_limit_reached: AtomicU64::new(0),
+            sticky_overrides: AtomicU64::new(0),
+            accept_queue_evicted: AtomicU64::new(0),
+            accept_queue_rejected: AtomicU64::new(0),
+            backend_requests: Mutex::new(HashMap::new()),
+            selection: Mutex::new(SelectionWindow::new(selection_window)),
+            request_duration: Mutex::new(Histogram::default()),
+            exemplars,
+        }
+    }
+
+    pub fn exemplars_enabled(&self) -> bool {
+        self.exemplars
+    }
+
+    /// Records how long a proxied request took, tagged with its trace ID when
+    /// there is one.
+    pub fn observe_duration(&self, duration: Duration, trace_id: Option<&str>) {
+        let trace_id = if self.exemplars { trace_id } else { None };
+        self.request_duration.lock().unwrap().observe(duration.as_secs_f64(), trace_id);
+    }
+
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that `backend` was picked to serve a request.
+    pub fn record_selection(&self, backend: &str) {
+        *self.backend_requests.lock().unwrap().entry(backend.to_string()).or_default() += 1;
+        self.selection.lock().unwrap().record(backend);
+    }
+
+    /// Fraction of the requests in the current window that went to each
+    /// backend, sorted by backend. Backends that have served requests before
+    /// but none in this window are listed with a share of zero.
+    pub fn selection_shares(&self) -> Vec<(String, f64)> {
+        let counts = self.selection.lock().unwrap().counts();
+        let total: u64 = counts.values().sum();
+
+        let mut backends: Vec<String> = self.backend_requests.lock().unwrap().keys().cloned().collect();
+        backends.sort();
+
+        backends
+            .into_iter()
+            .map(|backend| {
+                let count = counts.get(&backend).copied().unwrap_or(0);
+                let share = if total == 0 { 0.0 } else { count as f64 / total as f64 };
+                (backend, share)
+            })
+            .collect()
+    }
+
+    /// Renders every metric. OpenMetrics output names counter families
+    /// without the `_total` suffix, carries exemplars if they are enabled and
+    /// ends with the required `# EOF`.
+    pub fn render(&self, openmetrics: bool) -> String {
+        let mut out = String::new();
+
+        write_family(
+            &mut out,
+            "lancer_connection_limit_violations_total",
+            "counter",
+            "Client connections cut off by a per-connection limit.",
+            openmetrics,
+        );
+        for (reason, counter) in [
+            ("pipelined", &self.pipelined_limit_exceeded),
+            ("max_requests", &self.request_limit_reached),
+        ] {
+            let _ = writeln!(
+                out,
+                "lancer_connection_limit_violations_total{{reason=\"{}\"}} {}",
+                reason,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        write_family(
+            &mut out,
+            "lancer_dropped_connections_total",
+            "counter",
+            "Accepted client connections closed without being served.",
+            openmetrics,
+        );
+        for (reason, counter) in [
+            ("queue_evicted", &self.accept_queue_evicted),
+            ("queue_full", &self.accept_queue_rejected),
+        ] {
+            let _ = writeln!(
+                out,
+                "lancer_dropped_connections_total{{reason=\"{}\"}} {}",
+                reason,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        write_family(
+            &mut out,
+            "lancer_sticky_overrides_total",
+            "counter",
+            "Requests routed round-robin because their sticky backend was too busy.",
+            openmetrics,
+        );
+        let _ = writeln!(out, "lancer_sticky_overrides_total {}", self.sticky_overrides.load(Ordering::Relaxed));
+
+        let mut backend_requests: Vec<(String, u64)> = self
+            .backend_requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(backend, count)| (backend.clone(), *count))
+            .collect();
+        backend_requests.sort();
+
+        write_family(&mut out, "lancer_backend_requests_total", "counter", "Requests sent to each backend.", openmetrics);
+        for (backend, count) in backend_requests {
+            let _ = writeln!(out, "lancer_backend_requests_total{{backend=\"{}\"}} {}", backend, count);
+        }
+
+        write_family(
+            &mut out,
+            "lancer_backend_selection_share",
+            "gauge",
+            "Fraction of requests sent to each backend over the selection window.",
+            openmetrics,
+        );
+        for (backend, share) in self.selection_shares() {
+            let _ = writeln!(out, "lancer_backend_selection_share{{backend=\"{}\"}} {:.4}", backend, share);
+        }
+
+        write_family(
+            &mut out,
+            "lancer_request_duration_seconds",
+            "histogram",
+            "Time from receiving a request to finishing its response.",
+            openmetrics,
+        );
+        self.request_duration.lock().unwrap().render(&mut out, openmetrics && self.exemplars);
+
+        if openmetrics {
+            out.push_str("# EOF\n");
+        }
+        out
+    }
+}
+
+fn write_family(out: &mut String, name: &str, kind: &str, help: &str, openmetrics: bool) {
+    let family = if openmetrics && kind == "counter" {
+        name.trim_end_matches("_total")
+    } else {
+        name
+    };
+    let _ = writeln!(out, "# HELP {} {}", family, help);
+    let _ = writeln!(out, "# TYPE {} {}", family, kind);
+}
+
+/// A sample request that landed in a histogram bucket.
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    /// Seconds since the Unix epoch.
+    timestamp: f64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Non-cumulative counts, one per bucket plus one for `+Inf`.
+    counts: [u64; DURATION_BUCKETS.len() + 1],
+    /// The latest traced request in each bucket.
+    exemplars: [Option<Exemplar>; DURATION_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64, trace_id: Option<&str>) {
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+
+        if let Some(trace_id) = trace_id {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0);
+            self.exemplars[bucket] = Some(Exemplar {
+                trace_id: trace_id.to_string(),
+                value,
+                timestamp,
+            });
+        }
+    }
+
+    fn render(&self, out: &mut String, exemplars: bool) {
+        let mut cumulative = 0;
+
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count;
+            let bound = match DURATION_BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = write!(out, "lancer_request_duration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+
+            if let (true, Some(exemplar)) = (exemplars, &self.exemplars[i]) {
+                let _ = write!(
+                    out,
+                    " # {{trace_id=\"{}\"}} {} {:.3}",
+                    exemplar.trace_id, exemplar.value, exemplar.timestamp
+                );
+            }
+            out.push('\n');
+        }
+
+        let _ = writeln!(out, "lancer_request_duration_seconds_sum {}", self.sum);
+        let _ = writeln!(out, "lancer_request_duration_seconds_count {}", self.count);
+    }
+}
+
+/// Per-backend selection counts over a sliding window, kept as a ring of
+/// equal slots so old traffic ages out without storing every request.
+struct SelectionWindow {
+    window: Duration,
+    slot_len: Duration,
+    slots: VecDeque<(Instant, HashMap<String, u64>)>,
+}
+
+impl SelectionWindow {
+    fn new(window: Duration) -> Self {
+        SelectionWindow {
+            window,
+            slot_len: window / WINDOW_SLOTS,
+            slots: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, backend: &str) {
+        let now = Instant::now();
+        self.expire(now);
+
+        let current = match self.slots.back() {
+            Some((start, _)) => now.duration_since(*start) < self.slot_len,
+            None => false,
+        };
+        if !current {
+            self.slots.push_back((now, HashMap::new()));
+        }
+
+        let (_, counts) = self.slots.back_mut().unwrap();
+        *counts.entry(backend.to_string()).or_default() += 1;
+    }
+
+    fn counts(&mut self) -> HashMap<String, u64> {
+        self.expire(Instant::now());
+
+        let mut totals = HashMap::new();
+        for (_, counts) in &self.slots {
+            for (backend, count) in counts {
+                *totals.entry(backend.clone()).or_default() += count;
+            }
+        }
+        totals
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((start, _)) = self.slots.front() {
+            if now.duration_since(*start) < self.window {
+                break;
+            }
+            self.slots.pop_front();
+        }
+    }
+}
@@ -0,0 +1,521 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::TcpStream;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{Config, PoolSizing};
+use crate::probe;
+
+/// Adaptive sizing looks at the peak in-use count over this many adjustment
+/// intervals.
+const PEAK_WINDOW: usize = 6;
+
+/// Identifies one checkout of a connection. Every checkout gets a fresh ID,
+/// so a stale handle can never match a slot that has since been reused.
+type CheckoutId = u64;
+
+enum PooledConnection {
+    Idle(TcpStream),
+    InUse(CheckoutId),
+}
+
+/// Checkouts dropped without being handed back, waiting for the pool to free
+/// their slots. Kept apart from the pool itself so a handle can be dropped
+/// while the pool is locked.
+type Abandoned = Arc<Mutex<Vec<(String, CheckoutId)>>>;
+
+/// A connection checked out of the pool. It is handed back through
+/// `release_connection` or `discard_connection`, both of which consume it,
+/// and they only ever touch the slot it was checked out from. Dropping it
+/// instead, say on an early return, discards it.
+pub struct PooledStream {
+    id: CheckoutId,
+    server: String,
+    /// Only taken when the connection goes back into the pool.
+    stream: Option<TcpStream>,
+    reused: bool,
+    abandoned: Abandoned,
+}
+
+impl PooledStream {
+    /// Whether the connection was idle in the pool rather than newly opened.
+    /// The backend may have closed it just before it was handed out.
+    pub fn is_reused(&self) -> bool {
+        self.reused
+    }
+}
+
+impl Deref for PooledStream {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        self.stream.as_ref().expect("pooled connection used after being returned")
+    }
+}
+
+impl DerefMut for PooledStream {
+    fn deref_mut(&mut self) -> &mut TcpStream {
+        self.stream.as_mut().expect("pooled connection used after being returned")
+    }
+}
+
+impl Drop for PooledStream {
+    fn drop(&mut self) {
+        if self.stream.is_some() {
+            self.abandoned.lock().unwrap().push((self.server.clone(), self.id));
+        }
+    }
+}
+
+/// How many connections one backend may have. In static mode these are the
+/// configured maximums; in adaptive mode they follow observed demand.
+struct BackendLimits {
+    max_idle: usize,
+    /// Idle connections kept open ahead of demand. Always zero in static mode.
+    idle_floor: usize,
+    max_connections: usize,
+    /// Highest in-use count seen since the last adjustment.
+    current_peak: usize,
+    /// Peaks from the most recent adjustment intervals, oldest first.
+    recent_peaks: VecDeque<usize>,
+}
+
+pub struct ConnectionPool {
+    connections: HashMap<String, Vec<PooledConnection>>,
+    limits: HashMap<String, BackendLimits>,
+    sizing: PoolSizing,
+    next_id: CheckoutId,
+    abandoned: Abandoned,
+}
+
+impl ConnectionPool {
+
+    pub fn new(config: &Config) -> Self {
+        let sizing = config.pool_sizing.clone();
+        let (max_idle, idle_floor, max_connections) = match &sizing {
+            PoolSizing::Static { max_idle, max_connections } => (*max_idle, 0, *max_connections),
+            // Start at the bottom of the bounds; demand raises the cap as soon
+            // as it shows up. The idle cap stays at the upper bound so the
+            // floor can always be kept.
+            PoolSizing::Adaptive { min_idle, max_idle, min_connections, .. } => (*max_idle, *min_idle, *min_connections),
+        };
+
+        ConnectionPool {
+            connections: HashMap::new(),
+            limits: config
+                .backends
+                .iter()
+                .map(|server| {
+                    let limits = BackendLimits {
+                        max_idle,
+                        idle_floor,
+                        max_connections,
+                        current_peak: 0,
+                        recent_peaks: VecDeque::new(),
+                    };
+                    (server.clone(), limits)
+                })
+                .collect(),
+            sizing,
+            next_id: 0,
+            abandoned: Arc::default(),
+        }
+    }
+
+    fn checked_out(&self, id: CheckoutId, server: &str, stream: TcpStream, reused: bool) -> PooledStream {
+        PooledStream {
+            id,
+            server: server.to_string(),
+            stream: Some(stream),
+            reused,
+            abandoned: Arc::clone(&self.abandoned),
+        }
+    }
+
+    /// Frees the slots of checkouts that were dropped rather than handed back.
+    fn reclaim_abandoned(&mut self) {
+        let abandoned = std::mem::take(&mut *self.abandoned.lock().unwrap());
+        for (server, abandoned_id) in abandoned {
+            if let Some(connections) = self.connections.get_mut(&server) {
+                connections.retain(|c| !matches!(c, PooledConnection::InUse(id) if *id == abandoned_id));
+            }
+        }
+    }
+
+    pub fn get_connection(&mut self, server: &str) -> Result<PooledStream, IoError> {
+        let in_use = self.in_use(server);
+        let id = self.next_id;
+        self.next_id += 1;
+        let connections = self.connections.entry(server.to_string()).or_default();
+
+        if let Some(limits) = self.limits.get_mut(server) {
+            // Counted even when refused, so demand above the cap still shows.
+            limits.current_peak = limits.current_peak.max(in_use + 1);
+            if in_use >= limits.max_connections {
+                // An adaptive cap follows demand up right away, so a burst
+                // isn't refused while it waits for the next adjustment. Only
+                // the configured bound turns connections away.
+                match self.sizing {
+                    PoolSizing::Adaptive { max_connections, .. } if in_use < max_connections => {
+                        limits.max_connections = in_use + 1;
+                    }
+                    _ => return Err(IoError::new(ErrorKind::WouldBlock, "Connection limit reached")),
+                }
+            }
+        }
+
+        let mut i = 0;
+
+        while i < connections.len() {
+            if let PooledConnection::Idle(socket) = &connections[i] {
+                // An idle connection should have nothing to read; data or EOF
+                // means the backend closed it or sent something unasked for.
+                if !probe::has_stale_data(socket) {
+                    let conn = std::mem::replace(&mut connections[i], PooledConnection::InUse(id));
+
+                    if let PooledConnection::Idle(socket) = conn {
+                        return Ok(self.checked_out(id, server, socket, true));
+                    } else {
+                        // This should never happen, but we need to handle it for completeness
+                        unreachable!("Connection state changed unexpectedly");
+                    }
+                } else {
+                    connections.remove(i);
+                    continue;
+                }
+            }
+            i += 1;
+        }
+
+        // If no available connection, create a new one
+        let stream = connect(server)?;
+        connections.push(PooledConnection::InUse(id));
+        Ok(self.checked_out(id, server, stream, false))
+    }
+
+    /// Number of connections to `server` currently checked out.
+    pub fn in_use(&mut self, server: &str) -> usize {
+        self.reclaim_abandoned();
+        self.connections.get(server).map_or(0, |connections| {
+            connections.iter().filter(|c| matches!(c, PooledConnection::InUse(_))).count()
+        })
+    }
+
+    /// Finds the slot `stream` was checked out from. A handle whose slot is
+    /// gone is logged and ignored rather than put somewhere else.
+    fn checkout_slot(&mut self, server: &str, stream: &PooledStream, action: &str) -> Option<usize> {
+        let connections = self.connections.get_mut(server)?;
+        let slot = connections
+            .iter()
+            .position(|c| matches!(c, PooledConnection::InUse(id) if *id == stream.id));
+
+        debug_assert!(
+            connections
+                .iter()
+                .filter(|c| matches!(c, PooledConnection::InUse(id) if *id == stream.id))
+                .count()
+                <= 1,
+            "checkout {} of {} occupies more than one slot",
+            stream.id,
+            server
+        );
+
+        if slot.is_none() {
+            eprintln!("Ignoring {} of connection {} to {}: not checked out", action, stream.id, server);
+        }
+        slot
+    }
+
+    pub fn release_connection(&mut self, server: &str, mut stream: PooledStream) {
+        let idle_limit = self.limits.get(server).map_or(usize::MAX, |limits| limits.max_idle);
+        let socket = stream.stream.take();
+
+        if let Some(i) = self.checkout_slot(server, &stream, "release") {
+            let connections = self.connections.get_mut(server).unwrap();
+            let idle = connections.iter().filter(|c| matches!(c, PooledConnection::Idle(_))).count();
+
+            match socket {
+                Some(socket) if socket.peer_addr().is_ok() && idle < idle_limit => {
+                    connections[i] = PooledConnection::Idle(socket);
+                }
+                // Closed by the peer, or more idle connections than we keep:
+                // let this one go rather than leaving its slot counted as in use.
+                _ => {
+                    connections.remove(i);
+                }
+            }
+        }
+    }
+
+    /// Drops a checked-out connection instead of returning it to the pool.
+    pub fn discard_connection(&mut self, server: &str, mut stream: PooledStream) {
+        stream.stream.take();
+        if let Some(i) = self.checkout_slot(server, &stream, "discard") {
+            self.connections.get_mut(server).unwrap().remove(i);
+        }
+    }
+
+    /// Moves each backend's connection cap toward its rolling peak in-use
+    /// count plus headroom, and its idle floor toward the peak itself. The cap
+    /// rises to its target at once but shrinks, like the floor, by at most a
+    /// quarter of the current value (and at least one) per call. Results
+    /// always stay within the configured bounds. Does nothing in static mode.
+    pub fn adjust_sizes(&mut self) {
+        let (headroom, min_idle, max_idle, min_connections, max_connections) = match self.sizing {
+            PoolSizing::Static { .. } => return,
+            PoolSizing::Adaptive { headroom, min_idle, max_idle, min_connections, max_connections, .. } => {
+                (headroom, min_idle, max_idle, min_connections, max_connections)
+            }
+        };
+        self.reclaim_abandoned();
+
+        for (server, limits) in &mut self.limits {
+            // Count what is checked out right now too, in case a long request
+            // has held connections through the whole interval.
+            let in_use = self.connections.get(server).map_or(0, |connections| {
+                connections.iter().filter(|c| matches!(c, PooledConnection::InUse(_))).count()
+            });
+            limits.recent_peaks.push_back(limits.current_peak.max(in_use));
+            if limits.recent_peaks.len() > PEAK_WINDOW {
+                limits.recent_peaks.pop_front();
+            }
+            limits.current_peak = in_use;
+
+            let peak = limits.recent_peaks.iter().copied().max().unwrap_or(0);
+            let target_connections = ((peak as f64 * (1.0 + headroom)).ceil() as usize)
+                .clamp(min_connections, max_connections);
+            let target_floor = peak.clamp(min_idle, max_idle);
+
+            let new_connections = if target_connections > limits.max_connections {
+                target_connections
+            } else {
+                step_toward(limits.max_connections, target_connections)
+            };
+            let new_floor = step_toward(limits.idle_floor, target_floor);
+
+            if new_connections != limits.max_connections || new_floor != limits.idle_floor {
+                println!(
+                    "Pool for {} resized: max connections {} -> {}, idle floor {} -> {}",
+                    server, limits.max_connections, new_connections, limits.idle_floor, new_floor
+                );
+                limits.max_connections = new_connections;
+                limits.idle_floor = new_floor;
+            }
+        }
+    }
+
+    /// How many more idle connections each backend needs to reach its idle
+    /// floor, after letting go of idle ones the backend has closed. Opening
+    /// them is left to the caller, so the pool isn't locked while connecting.
+    pub fn idle_shortfall(&mut self) -> Vec<(String, usize)> {
+        let mut shortfall = Vec::new();
+
+        for (server, limits) in &self.limits {
+            let connections = self.connections.entry(server.clone()).or_default();
+            connections.retain(|c| !matches!(c, PooledConnection::Idle(socket) if probe::has_stale_data(socket)));
+
+            let idle = connections.iter().filter(|c| matches!(c, PooledConnection::Idle(_))).count();
+            if idle < limits.idle_floor {
+                shortfall.push((server.clone(), limits.idle_floor - idle));
+            }
+        }
+
+        shortfall
+    }
+
+    /// Adds a connection opened to make up an idle shortfall, unless the
+    /// floor has been reached in the meantime.
+    pub fn add_idle(&mut self, server: &str, stream: TcpStream) {
+        let floor = self.limits.get(server).map_or(0, |limits| limits.idle_floor);
+        let connections = self.connections.entry(server.to_string()).or_default();
+        let idle = connections.iter().filter(|c| matches!(c, PooledConnection::Idle(_))).count();
+
+        if idle < floor {
+            connections.push(PooledConnection::Idle(stream));
+        }
+    }
+}
+
+/// Opens a new connection to `server`.
+pub fn connect(server: &str) -> Result<TcpStream, IoError> {
+    let addr = server
+        .parse()
+        .map_err(|_| IoError::new(ErrorKind::InvalidInput, format!("Invalid backend address {}", server)))?;
+    TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+}
+
+fn step_toward(current: usize, target: usize) -> usize {
+    let step = (current / 4).max(1);
+    if target > current {
+        (current + step).min(target)
+    } else {
+        current.saturating_sub(step).max(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// A pool for one backend that accepts connections but never answers.
+    /// The listener has to outlive the test.
+    fn pool_with_backend() -> (TcpListener, String, ConnectionPool) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let mut config = Config::default();
+        config.backends = vec![server.clone()];
+        (listener, server, ConnectionPool::new(&config))
+    }
+
+    fn idle(pool: &ConnectionPool, server: &str) -> usize {
+        pool.connections[server]
+            .iter()
+            .filter(|c| matches!(c, PooledConnection::Idle(_)))
+            .count()
+    }
+
+    /// A second handle claiming checkout `id`, as a stale copy would.
+    fn forged(pool: &ConnectionPool, id: CheckoutId, server: &str, stream: &TcpStream) -> PooledStream {
+        pool.checked_out(id, server, stream.try_clone().unwrap(), true)
+    }
+
+    #[test]
+    fn release_frees_only_its_own_slot() {
+        let (_listener, server, mut pool) = pool_with_backend();
+        let first = pool.get_connection(&server).unwrap();
+        let second = pool.get_connection(&server).unwrap();
+        let first_id = first.id;
+
+        pool.release_connection(&server, second);
+
+        assert_eq!(pool.in_use(&server), 1);
+        assert_eq!(idle(&pool, &server), 1);
+        assert!(pool.connections[&server]
+            .iter()
+            .any(|c| matches!(c, PooledConnection::InUse(id) if *id == first_id)));
+
+        pool.release_connection(&server, first);
+        assert_eq!(pool.in_use(&server), 0);
+        assert_eq!(idle(&pool, &server), 2);
+    }
+
+    #[test]
+    fn double_release_is_ignored() {
+        let (_listener, server, mut pool) = pool_with_backend();
+        let stream = pool.get_connection(&server).unwrap();
+        let stale = forged(&pool, stream.id, &server, &stream);
+
+        pool.release_connection(&server, stream);
+        pool.release_connection(&server, stale);
+
+        assert_eq!(pool.in_use(&server), 0);
+        assert_eq!(idle(&pool, &server), 1);
+
+        // The idle connection is handed out once, under a fresh ID.
+        let again = pool.get_connection(&server).unwrap();
+        assert_eq!(pool.in_use(&server), 1);
+        assert_eq!(idle(&pool, &server), 0);
+        pool.discard_connection(&server, again);
+        assert!(pool.connections[&server].is_empty());
+    }
+
+    #[test]
+    fn foreign_checkout_is_ignored() {
+        let (_listener, server, mut pool) = pool_with_backend();
+        let stream = pool.get_connection(&server).unwrap();
+        let foreign = forged(&pool, stream.id + 100, &server, &stream);
+
+        pool.release_connection(&server, foreign);
+        assert_eq!(pool.in_use(&server), 1);
+        assert_eq!(idle(&pool, &server), 0);
+
+        let foreign = forged(&pool, stream.id + 100, &server, &stream);
+        pool.discard_connection(&server, foreign);
+        assert_eq!(pool.in_use(&server), 1);
+
+        pool.release_connection(&server, stream);
+        assert_eq!(pool.in_use(&server), 0);
+        assert_eq!(idle(&pool, &server), 1);
+    }
+
+    #[test]
+    fn adaptive_pool_keeps_its_idle_floor() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let mut config = Config::default();
+        config.backends = vec![server.clone()];
+        config.pool_sizing = PoolSizing::Adaptive {
+            headroom: 0.25,
+            min_idle: 2,
+            max_idle: 4,
+            min_connections: 4,
+            max_connections: 16,
+            adjust_interval: Duration::from_secs(10),
+        };
+        let mut pool = ConnectionPool::new(&config);
+
+        pool.adjust_sizes();
+        assert_eq!(pool.idle_shortfall(), [(server.clone(), 2)]);
+
+        for _ in 0..3 {
+            pool.add_idle(&server, connect(&server).unwrap());
+        }
+        assert_eq!(idle(&pool, &server), 2);
+        assert!(pool.idle_shortfall().is_empty());
+
+        // Idle connections count toward the floor until they are checked out.
+        let stream = pool.get_connection(&server).unwrap();
+        assert_eq!(pool.idle_shortfall(), [(server.clone(), 1)]);
+        pool.release_connection(&server, stream);
+        assert!(pool.idle_shortfall().is_empty());
+    }
+
+    #[test]
+    fn dropped_checkout_frees_its_slot() {
+        let (_listener, server, mut pool) = pool_with_backend();
+        let kept = pool.get_connection(&server).unwrap();
+        let dropped = pool.get_connection(&server).unwrap();
+
+        drop(dropped);
+
+        assert_eq!(pool.in_use(&server), 1);
+        pool.release_connection(&server, kept);
+        assert_eq!(pool.in_use(&server), 0);
+        assert_eq!(idle(&pool, &server), 1);
+    }
+
+    #[test]
+    fn adaptive_cap_grows_with_a_burst() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let mut config = Config::default();
+        config.backends = vec![server.clone()];
+        config.pool_sizing = PoolSizing::Adaptive {
+            headroom: 0.25,
+            min_idle: 0,
+            max_idle: 4,
+            min_connections: 2,
+            max_connections: 6,
+            adjust_interval: Duration::from_secs(10),
+        };
+        let mut pool = ConnectionPool::new(&config);
+
+        // Well past the starting cap, up to the configured bound.
+        let burst: Vec<_> = (0..6).map(|_| pool.get_connection(&server).unwrap()).collect();
+        assert!(pool.get_connection(&server).is_err());
+
+        // Once the burst is over the cap comes down a step at a time.
+        for stream in burst {
+            pool.release_connection(&server, stream);
+        }
+        for _ in 0..PEAK_WINDOW {
+            pool.adjust_sizes();
+        }
+        assert_eq!(pool.limits[&server].max_connections, 6);
+        pool.adjust_sizes();
+        assert_eq!(pool.limits[&server].max_connections, 5);
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::ErrorKind;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::http::{self, ResponseHead};
+
+/// Header carrying the per-request token when `probe_connections` is on.
+pub const PROBE_HEADER: &str = "X-Lancer-Probe";
+
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// A token unique to this request. The start time keeps tokens from one run
+/// from matching leftovers from a previous one.
+pub fn next_token() -> String {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("{:x}-{:x}", started, NEXT_TOKEN.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A pooled connection should be silent between requests. Anything already
+/// waiting to be read is a response to some earlier request and would be
+/// handed to the wrong client, and an end of stream means the backend has
+/// closed it.
+pub fn has_stale_data(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+
+    let mut byte = [0; 1];
+    let stale = match stream.peek(&mut byte) {
+        Ok(_) => true,
+        Err(e) => e.kind() != ErrorKind::WouldBlock,
+    };
+
+    stream.set_nonblocking(false).is_err() || stale
+}
+
+/// Checks that a backend response belongs to the request tagged with `token`.
+/// Backends that echo the probe header must echo it unchanged, and when the
+/// response is length-delimited nothing may follow its body.
+pub fn verify_response(response: &[u8], token: &str) -> Result<(), String> {
+    let head = match ResponseHead::parse(response) {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+
+    if let Some(echoed) = head.header(PROBE_HEADER) {
+        if echoed != token {
+            return Err(format!("expected probe token {}, backend echoed {}", token, echoed));
+        }
+    }
+
+    if let Some(length) = head.content_length() {
+        let expected = head.len + length;
+        if response.len() > expected {
+            return Err(format!(
+                "{} unexpected bytes after a {} byte response",
+                response.len() - expected,
+                expected
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the probe header from the heads of `response`, including any
+/// interim 1xx responses ahead of the final one.
+pub fn strip_header(mut response: Vec<u8>) -> Vec<u8> {
+    let mut offset = 0;
+
+    while let Some(mut head) = ResponseHead::parse(&response[offset..]) {
+        if head.header(PROBE_HEADER).is_some() {
+            let rebuilt = http::edit_headers(&response[offset..], |headers| {
+                http::remove_header(headers, PROBE_HEADER);
+            });
+            response.truncate(offset);
+            response.extend_from_slice(&rebuilt);
+            head = match ResponseHead::parse(&response[offset..]) {
+                Some(head) => head,
+                None => break,
+            };
+        }
+
+        if !(100..200).contains(&head.status) || head.status == 101 {
+            break;
+        }
+        offset += head.len;
+    }
+
+    response
+}
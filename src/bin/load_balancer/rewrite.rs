@@ -0,0 +1,121 @@
+use crate::http::{self, ResponseHead};
+
+/// Replaces absolute links to the configured internal hosts in small HTML
+/// responses with `external_host`. Responses that aren't HTML, are larger than
+/// `max_bytes`, are compressed or chunked, or whose body doesn't match their
+/// declared length are returned untouched, as are all responses when
+/// `external_host` isn't a plain host and port. Content-Length is recomputed
+/// after rewriting.
+pub fn rewrite_html(response: Vec<u8>, internal_hosts: &[String], external_host: &str, max_bytes: usize) -> Vec<u8> {
+    // The client's Host header ends up in the page, so it must not be able
+    // to carry markup.
+    if !is_authority(external_host) {
+        return response;
+    }
+
+    let head = match ResponseHead::parse(&response) {
+        Some(head) => head,
+        None => return response,
+    };
+
+    let is_html = head
+        .header("Content-Type")
+        .is_some_and(|value| value.to_ascii_lowercase().starts_with("text/html"));
+    let body_len = response.len() - head.len;
+
+    if !is_html
+        || body_len > max_bytes
+        || head.header("Content-Encoding").is_some_and(|value| !value.eq_ignore_ascii_case("identity"))
+        || head.header("Transfer-Encoding").is_some()
+        || head.content_length().is_some_and(|length| length != body_len)
+    {
+        return response;
+    }
+
+    let mut body = response[head.len..].to_vec();
+    for internal in internal_hosts {
+        // Matching on `//host` catches both `http://host` and
+        // scheme-relative `//host` links without touching plain text.
+        body = replace_host(&body, format!("//{}", internal).as_bytes(), format!("//{}", external_host).as_bytes());
+    }
+
+    if body.len() == body_len && body == response[head.len..] {
+        return response;
+    }
+
+    let mut rewritten = http::edit_headers(&response[..head.len], |headers| {
+        http::set_header(headers, "Content-Length", &body.len().to_string());
+    });
+    rewritten.extend_from_slice(&body);
+    rewritten
+}
+
+/// Whether `host` is a host name or address with an optional port.
+fn is_authority(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b':' | b'[' | b']'))
+}
+
+/// Replaces `from` wherever the host in it ends there, so `//internal:80`
+/// doesn't match the start of `//internal:8081`.
+fn replace_host(haystack: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(haystack.len());
+    let ends_host = |end: usize| {
+        haystack
+            .get(end)
+            .is_none_or(|b| matches!(b, b'/' | b'"' | b'\'' | b'?' | b'#'))
+    };
+    let mut i = 0;
+
+    while i < haystack.len() {
+        if haystack[i..].starts_with(from) && ends_host(i + from.len()) {
+            result.extend_from_slice(to);
+            i += from.len();
+        } else {
+            result.push(haystack[i]);
+            i += 1;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(body: &str) -> Vec<u8> {
+        format!("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).into_bytes()
+    }
+
+    fn rewrite(body: &str, external_host: &str) -> Vec<u8> {
+        rewrite_html(html(body), &["internal:80".to_string()], external_host, 1024)
+    }
+
+    #[test]
+    fn rewrites_links_and_content_length() {
+        assert_eq!(
+            rewrite("<a href=\"http://internal:80/x\">x</a> <img src='//internal:80'>", "example.com"),
+            html("<a href=\"http://example.com/x\">x</a> <img src='//example.com'>")
+        );
+    }
+
+    #[test]
+    fn host_must_end_at_a_delimiter() {
+        let body = "<a href=\"http://internal:8081/x\">x</a> //internal:80.evil";
+        assert_eq!(rewrite(body, "example.com"), html(body));
+    }
+
+    #[test]
+    fn unsafe_external_host_is_not_used() {
+        let body = "<a href=\"http://internal:80/x\">x</a>";
+        assert_eq!(rewrite(body, "x\"><script>"), html(body));
+        assert_eq!(rewrite(body, ""), html(body));
+        assert_eq!(
+            rewrite(body, "[::1]:8080"),
+            html("<a href=\"http://[::1]:8080/x\">x</a>")
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::http::RequestHead;
+
+/// W3C Trace Context header.
+pub const TRACEPARENT: &str = "traceparent";
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The trace a request belongs to.
+pub struct TraceContext {
+    pub trace_id: String,
+    /// A new `traceparent` to send to the backend, set when the client didn't
+    /// send a valid one.
+    pub inject: Option<String>,
+}
+
+/// Continues the client's trace if it sent a valid `traceparent`, otherwise
+/// starts a new one.
+pub fn trace_context(head: &RequestHead) -> TraceContext {
+    if let Some(trace_id) = head.header(TRACEPARENT).and_then(parse_trace_id) {
+        return TraceContext {
+            trace_id: trace_id.to_string(),
+            inject: None,
+        };
+    }
+
+    let trace_id = format!("{:016x}{:016x}", random_u64(), random_u64());
+    let inject = format!("00-{}-{:016x}-01", trace_id, random_u64());
+    TraceContext {
+        trace_id,
+        inject: Some(inject),
+    }
+}
+
+/// Extracts the trace ID from a version 00 `traceparent` value.
+fn parse_trace_id(value: &str) -> Option<&str> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+
+    let is_hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+
+    let valid = version == "00"
+        && parts.next().is_none()
+        && is_hex(trace_id, 32)
+        && is_hex(parent_id, 16)
+        && is_hex(flags, 2)
+        && trace_id.bytes().any(|b| b != b'0')
+        && parent_id.bytes().any(|b| b != b'0');
+
+    valid.then_some(trace_id)
+}
+
+/// IDs only need to be unique, not unpredictable, so std's randomly keyed
+/// hasher over a counter and the clock is enough.
+fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+    hasher.finish()
+}
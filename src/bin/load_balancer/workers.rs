@@ -0,0 +1,94 @@
+use std::collections::VecDeque;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::config::QueuePolicy;
+use crate::lifecycle::ConnectionGuard;
+use crate::metrics::Metrics;
+
+/// An accepted connection and the guard counting it as open.
+type Queued = (TcpStream, ConnectionGuard);
+
+/// A fixed set of threads serving client connections, fed through a bounded
+/// queue by the accept loops.
+pub struct WorkerPool {
+    queue: Mutex<VecDeque<Queued>>,
+    available: Condvar,
+    capacity: usize,
+    policy: QueuePolicy,
+    metrics: Arc<Metrics>,
+}
+
+impl WorkerPool {
+    /// Starts `workers` threads that each take connections off the queue and
+    /// hand them to `handle`.
+    pub fn spawn<F>(workers: usize, capacity: usize, policy: QueuePolicy, metrics: Arc<Metrics>, handle: F) -> Arc<Self>
+    where
+        F: Fn(TcpStream, ConnectionGuard) + Send + Sync + 'static,
+    {
+        let pool = Arc::new(WorkerPool {
+            queue: Mutex::new(VecDeque::with_capacity(capacity)),
+            available: Condvar::new(),
+            capacity,
+            policy,
+            metrics,
+        });
+
+        let handle = Arc::new(handle);
+        for _ in 0..workers {
+            let pool = Arc::clone(&pool);
+            let handle = Arc::clone(&handle);
+            thread::spawn(move || loop {
+                let (stream, guard) = pool.take();
+                handle(stream, guard);
+            });
+        }
+
+        pool
+    }
+
+    /// Queues `stream` for the next free worker. When the queue is full one
+    /// connection is closed: the oldest waiting one or `stream` itself,
+    /// depending on the policy.
+    pub fn submit(&self, stream: TcpStream, guard: ConnectionGuard) {
+        let mut queue = self.queue.lock().unwrap();
+
+        let dropped = if queue.len() < self.capacity {
+            queue.push_back((stream, guard));
+            None
+        } else {
+            match self.policy {
+                // The oldest connection has waited longest, so its client is
+                // the most likely to have given up already.
+                QueuePolicy::DropOldest => {
+                    Metrics::increment(&self.metrics.accept_queue_evicted);
+                    let oldest = queue.pop_front();
+                    queue.push_back((stream, guard));
+                    oldest
+                }
+                QueuePolicy::DropNewest => {
+                    Metrics::increment(&self.metrics.accept_queue_rejected);
+                    Some((stream, guard))
+                }
+            }
+        };
+
+        drop(queue);
+        self.available.notify_one();
+
+        if let Some((stream, _guard)) = dropped {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    fn take(&self) -> Queued {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            match queue.pop_front() {
+                Some(stream) => return stream,
+                None => queue = self.available.wait(queue).unwrap(),
+            }
+        }
+    }
+}
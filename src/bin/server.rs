@@ -6,6 +6,7 @@ fn main() {
     let args: Vec<String> = std::env::args().collect();
     let port = &args[1];
     let server_name = &args[2];
+    let health_path = args.get(3).cloned().unwrap_or_else(|| "/health".to_string());
 
     let addr = format!("127.0.0.1:{}", port);
     let listener = TcpListener::bind(&addr).unwrap();
@@ -14,23 +15,31 @@ fn main() {
     for stream in listener.incoming() {
         let stream = stream.unwrap();
         let server_name = server_name.clone();
+        let health_path = health_path.clone();
 
         thread::spawn(move || {
-            handle_connection(stream, &server_name);
+            handle_connection(stream, &server_name, &health_path);
         });
     }
 }
 
-fn handle_connection(mut stream: std::net::TcpStream, server_name: &str) {
+fn handle_connection(mut stream: std::net::TcpStream, server_name: &str, health_path: &str) {
     let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
+    let bytes_read = stream.read(&mut buffer).unwrap();
 
-    let request = String::from_utf8_lossy(&buffer[..]);
+    let request = String::from_utf8_lossy(&buffer[..bytes_read]);
     let first_line = request.lines().next().unwrap_or("");
 
-    if first_line.starts_with("GET /health ") {
+    // Echo the balancer's probe token so it can match responses to requests.
+    let probe_header = request
+        .lines()
+        .find(|line| line.to_ascii_lowercase().starts_with("x-lancer-probe:"))
+        .map(|line| format!("{}\r\n", line))
+        .unwrap_or_default();
+
+    if first_line.starts_with(&format!("GET {} ", health_path)) {
         let response = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nOK";
-        stream.write(response.as_bytes()).unwrap();
+        stream.write_all(response.as_bytes()).unwrap();
         stream.flush().unwrap();
         return;
     }
@@ -43,11 +52,11 @@ fn handle_connection(mut stream: std::net::TcpStream, server_name: &str) {
     let factor_count = count_factors(number);
 
     let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nHello from {}, your factors are {}",
-        server_name, factor_count,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n{}\r\nHello from {}, your factors are {}",
+        probe_header, server_name, factor_count,
     );
 
-    stream.write(response.as_bytes()).unwrap();
+    stream.write_all(response.as_bytes()).unwrap();
     stream.flush().unwrap();
 }
 
@@ -57,7 +66,7 @@ fn count_factors(n: u64) -> u64 {
     }
     let mut count = 0;
     for i in 1..=n {
-        if n % i == 0 {
+        if n.is_multiple_of(i) {
             count += 1;
         }
     }